@@ -0,0 +1,78 @@
+use serde::Deserialize;
+use std::fs::read_to_string;
+use std::path::{Path, PathBuf};
+use super::errors::*;
+
+/// Settings read from the config file, anything left out of which keeps its built-in default.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+	pub selectors: SelectorConfig,
+}
+
+/// The selectors used to scrape WuxiaWorld, so changes to its markup can be worked around without a rebuild.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SelectorConfig {
+	pub book_title: String,
+	pub cover: String,
+	/// The labels of the author and translator details, whose values follow them.
+	pub author_labels: String,
+	/// The genres and tags the book is listed under, one per element.
+	pub tags: String,
+	pub chapter_links: String,
+	/// The publish date shown alongside a chapter link, looked for within the element holding the link.
+	pub chapter_dates: String,
+	/// Tried in order until one finds the chapter's paragraphs.
+	pub content: Vec<String>,
+	/// The links looked through for one to the rest of a chapter split across pages, which is followed if its
+	/// text reads "Next Page", or "Next" when it stays within the chapter (as the link to the next chapter can read
+	/// "Next" too).
+	pub next_page: String,
+	/// Paragraphs holding nothing but one of these (whitespace aside) mark scene breaks. Replaces the defaults,
+	/// so they need repeating to extend them.
+	pub scene_break_markers: Vec<String>,
+}
+
+impl Default for SelectorConfig {
+	fn default() -> SelectorConfig {
+		SelectorConfig {
+			book_title: ".p-15 h4".to_owned(),
+			cover: ".p-15 img".to_owned(),
+			author_labels: ".p-15 dt".to_owned(),
+			tags: ".genres a".to_owned(),
+			chapter_links: ".chapter-item a".to_owned(),
+			chapter_dates: "time".to_owned(),
+			content: vec![
+				".innerContent.fr-view p".to_owned(),
+				".fr-view > p".to_owned(),
+			],
+			next_page: "a".to_owned(),
+			scene_break_markers: ["***", "****", "*****", "* * *", "###", "~~~", "---", "———", "◇◇◇", "◆◆◆", "◇◆◇", "◆◇◆", "☆☆☆", "★★★", "ooo", "xxx"]
+				.iter()
+				.map(|marker| (*marker).to_owned())
+				.collect(),
+		}
+	}
+}
+
+/// Where the config file is looked for unless given with `--config`.
+pub fn default_config_path() -> Option<PathBuf> {
+	dirs::config_dir().map(|dir| dir.join("wuxia-dl").join("config.json"))
+}
+
+/// Reads the config file at `path`, or at the default path if there's one there, falling back to the defaults.
+pub fn load_config(path: Option<&Path>) -> Result<Config> {
+	let path = match path {
+		Some(path) => path.to_owned(),
+		None => match default_config_path() {
+			Some(ref path) if path.is_file() => path.clone(),
+			_ => return Ok(Config::default()),
+		},
+	};
+
+	let contents = read_to_string(&path)
+		.chain_err(|| format!("Unable to read config file: \"{}\"", path.display()))?;
+	serde_json::from_str(&contents)
+		.chain_err(|| format!("Invalid config file: \"{}\"", path.display()))
+}
@@ -0,0 +1,296 @@
+use chrono::{DateTime, NaiveDateTime, Utc};
+use reqwest::Response;
+use reqwest::header::SET_COOKIE;
+use std::fs::read_to_string;
+use std::path::Path;
+use std::sync::Mutex;
+use super::errors::*;
+use url::Url;
+
+/// A cookie, along with the hosts and paths it's sent to.
+#[derive(Debug, Clone)]
+struct Cookie {
+	name: String,
+	value: String,
+	/// The host the cookie belongs to, without any leading dot.
+	domain: String,
+	/// Whether it's also sent to every subdomain of `domain`.
+	subdomains: bool,
+	path: String,
+	/// Whether it's only ever sent over https.
+	secure: bool,
+	/// When it expires, as a Unix timestamp, or `None` for a cookie that lasts the session.
+	expires: Option<i64>,
+}
+
+impl Cookie {
+	fn matches(&self, url: &Url) -> bool {
+		let host = match url.host_str() {
+			Some(host) => host.to_ascii_lowercase(),
+			None => return false,
+		};
+		let domain_matches = host == self.domain
+			|| (self.subdomains && host.ends_with(&format!(".{}", self.domain)));
+		domain_matches
+			&& path_matches(url.path(), &self.path)
+			&& (!self.secure || url.scheme() == "https")
+			&& self.expires.is_none_or(|expires| expires > Utc::now().timestamp())
+	}
+}
+
+/// Whether a request for `path` is within the cookie's `cookie_path`, on a `/` boundary so `/foo` doesn't take in
+/// `/foobar`.
+fn path_matches(path: &str, cookie_path: &str) -> bool {
+	match path.strip_prefix(cookie_path) {
+		Some(rest) => rest.is_empty() || rest.starts_with('/') || cookie_path.ends_with('/'),
+		None => false,
+	}
+}
+
+/// Whether a response from `host` may set a cookie for `domain`, which has to be the host itself or one of the
+/// domains it's under.
+///
+/// Without a list of public suffixes, a domain has to have at least two labels, so `Domain=com` is turned down.
+fn may_set(host: &str, domain: &str) -> bool {
+	host == domain || (domain.contains('.') && host.ends_with(&format!(".{}", domain)))
+}
+
+/// Parses the date of an `Expires` attribute into a Unix timestamp.
+///
+/// Servers mostly send the RFC 1123 form (`Wed, 21 Oct 2015 07:28:00 GMT`), with the dashed RFC 850 one
+/// (`Wednesday, 21-Oct-15 07:28:00 GMT`) still about.
+fn parse_expires(value: &str) -> Option<i64> {
+	if let Ok(date) = DateTime::parse_from_rfc2822(value) {
+		return Some(date.timestamp());
+	}
+	["%a, %d-%b-%Y %H:%M:%S GMT", "%A, %d-%b-%y %H:%M:%S GMT"].iter()
+		.find_map(|format| NaiveDateTime::parse_from_str(value, format).ok())
+		.map(|date| date.and_utc().timestamp())
+}
+
+/// The cookies sent along with every request, seeded with a session from `--cookie` or `--cookie-file` and
+/// kept up to date with whatever the site sets in its responses, so one established for the table of contents
+/// carries through to every chapter.
+///
+/// Only cookies set by the response a request ended with are picked up, not ones set along a redirect.
+pub struct CookieJar {
+	cookies: Mutex<Vec<Cookie>>,
+}
+
+impl CookieJar {
+	pub fn new() -> CookieJar {
+		CookieJar {
+			cookies: Mutex::new(Vec::new()),
+		}
+	}
+
+	/// Adds the cookies of a raw `Cookie` header (`name=value; other=value`), sent to `host` and its subdomains.
+	pub fn add_header(&self, host: &str, header: &str) -> Result<()> {
+		for pair in header.split(';').map(str::trim).filter(|pair| !pair.is_empty()) {
+			let (name, value) = pair.split_once('=')
+									.chain_err(|| format!("Invalid cookie \"{}\", expected \"<name>=<value>\".", pair))?;
+			self.insert(Cookie {
+				name: name.trim().to_owned(),
+				value: value.trim().to_owned(),
+				domain: host.trim_start_matches('.').to_ascii_lowercase(),
+				subdomains: true,
+				path: "/".to_owned(),
+				secure: false,
+				expires: None,
+			});
+		}
+		Ok(())
+	}
+
+	/// Adds the cookies in a Netscape cookie file, as exported by most browser extensions and `curl`, leaving out the
+	/// ones that have already expired.
+	pub fn read_netscape_file(&self, path: &Path) -> Result<()> {
+		let contents = read_to_string(path)
+			.chain_err(|| format!("Unable to read cookie file: \"{}\"", path.display()))?;
+		let now = Utc::now().timestamp();
+
+		for (number, line) in contents.lines().enumerate() {
+			// Cookies only meant for HTTP are commented out like this, but are still cookies.
+			let line = line.strip_prefix("#HttpOnly_").unwrap_or(line);
+			if line.trim().is_empty() || line.starts_with('#') {
+				continue;
+			}
+
+			let fields: Vec<&str> = line.split('\t').collect();
+			if fields.len() != 7 {
+				bail!("Invalid cookie file \"{}\": line {} doesn't have 7 tab separated fields.", path.display(), number + 1);
+			}
+			let expires = fields[4].parse::<i64>()
+								   .chain_err(|| format!("Invalid cookie file \"{}\": line {} has an invalid expiry.", path.display(), number + 1))?;
+			// Session cookies don't expire.
+			let expires = if expires == 0 {
+				None
+			} else if expires < now {
+				continue;
+			} else {
+				Some(expires)
+			};
+
+			self.insert(Cookie {
+				name: fields[5].to_owned(),
+				value: fields[6].to_owned(),
+				domain: fields[0].trim_start_matches('.').to_ascii_lowercase(),
+				subdomains: fields[1].eq_ignore_ascii_case("TRUE"),
+				path: fields[2].to_owned(),
+				secure: fields[3].eq_ignore_ascii_case("TRUE"),
+				expires,
+			});
+		}
+		Ok(())
+	}
+
+	/// The `Cookie` header for a request to `url`, if any cookies are to be sent to it.
+	pub fn header_for(&self, url: &Url) -> Option<String> {
+		let cookies = self.cookies.lock().unwrap();
+		let header = cookies.iter()
+							.filter(|cookie| cookie.matches(url))
+							.map(|cookie| format!("{}={}", cookie.name, cookie.value))
+							.collect::<Vec<_>>()
+							.join("; ");
+		if header.is_empty() {
+			None
+		} else {
+			Some(header)
+		}
+	}
+
+	/// Stores the cookies `res` sets, and drops the ones it expires.
+	pub fn store(&self, res: &Response) {
+		let host = match res.url().host_str() {
+			Some(host) => host.to_ascii_lowercase(),
+			None => return,
+		};
+		for header in res.headers().get_all(SET_COOKIE).iter().filter_map(|value| value.to_str().ok()) {
+			self.set(&host, header);
+		}
+	}
+
+	/// Stores the cookie of a `Set-Cookie` header sent by `host`, or drops it if it's being expired.
+	fn set(&self, host: &str, header: &str) {
+		let mut parts = header.split(';').map(str::trim);
+		let (name, value) = match parts.next().and_then(|pair| pair.split_once('=')) {
+			Some((name, value)) => (name.trim(), value.trim()),
+			None => return,
+		};
+
+		let mut cookie = Cookie {
+			name: name.to_owned(),
+			value: value.to_owned(),
+			domain: host.to_owned(),
+			subdomains: false,
+			path: "/".to_owned(),
+			secure: false,
+			expires: None,
+		};
+		let mut max_age = None;
+		for attribute in parts {
+			let (key, value) = attribute.split_once('=').unwrap_or((attribute, ""));
+			match key.trim().to_ascii_lowercase().as_str() {
+				"domain" if !value.is_empty() => {
+					cookie.domain = value.trim().trim_start_matches('.').to_ascii_lowercase();
+					cookie.subdomains = true;
+				}
+				"path" if value.starts_with('/') => cookie.path = value.to_owned(),
+				"secure" => cookie.secure = true,
+				"expires" => cookie.expires = cookie.expires.or_else(|| parse_expires(value.trim())),
+				"max-age" => max_age = value.trim().parse::<i64>().ok(),
+				_ => {}
+			}
+		}
+		// `Max-Age` wins over `Expires` when both are given.
+		if let Some(age) = max_age {
+			cookie.expires = Some(Utc::now().timestamp().saturating_add(age));
+		}
+
+		// A site can't set cookies for another.
+		if !may_set(host, &cookie.domain) {
+			return;
+		}
+		if cookie.expires.is_some_and(|expires| expires <= Utc::now().timestamp()) {
+			self.remove(&cookie);
+		} else {
+			self.insert(cookie);
+		}
+	}
+
+	fn insert(&self, cookie: Cookie) {
+		self.remove(&cookie);
+		self.cookies.lock().unwrap().push(cookie);
+	}
+
+	fn remove(&self, cookie: &Cookie) {
+		self.cookies
+			.lock()
+			.unwrap()
+			.retain(|c| c.name != cookie.name || c.domain != cookie.domain || c.path != cookie.path);
+	}
+}
+
+impl Default for CookieJar {
+	fn default() -> CookieJar {
+		CookieJar::new()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn url(url: &str) -> Url {
+		Url::parse(url).unwrap()
+	}
+
+	#[test]
+	fn expired_cookies_are_dropped() {
+		let jar = CookieJar::new();
+		jar.set("www.wuxiaworld.com", "session=abc");
+		jar.set("www.wuxiaworld.com", "other=def; Expires=Wed, 21 Oct 2037 07:28:00 GMT");
+		assert_eq!(jar.header_for(&url("https://www.wuxiaworld.com/")).as_deref(), Some("session=abc; other=def"));
+
+		jar.set("www.wuxiaworld.com", "session=; Expires=Thu, 01 Jan 1970 00:00:00 GMT");
+		jar.set("www.wuxiaworld.com", "other=; Max-Age=0");
+		assert_eq!(jar.header_for(&url("https://www.wuxiaworld.com/")), None);
+	}
+
+	#[test]
+	fn max_age_wins_over_expires() {
+		let jar = CookieJar::new();
+		jar.set("www.wuxiaworld.com", "session=abc; Max-Age=3600; Expires=Thu, 01 Jan 1970 00:00:00 GMT");
+		assert_eq!(jar.header_for(&url("https://www.wuxiaworld.com/")).as_deref(), Some("session=abc"));
+	}
+
+	#[test]
+	fn dashed_expiry_dates_are_understood() {
+		assert_eq!(parse_expires("Monday, 01-Jan-90 00:00:00 GMT"), Some(631152000));
+		assert_eq!(parse_expires("Mon, 01-Jan-1990 00:00:00 GMT"), Some(631152000));
+		assert_eq!(parse_expires("Mon, 01 Jan 1990 00:00:00 GMT"), Some(631152000));
+		assert_eq!(parse_expires("tomorrow"), None);
+	}
+
+	#[test]
+	fn cookies_for_top_level_domains_are_turned_down() {
+		let jar = CookieJar::new();
+		jar.set("www.wuxiaworld.com", "a=1; Domain=com");
+		jar.set("www.wuxiaworld.com", "b=2; Domain=.com");
+		jar.set("www.wuxiaworld.com", "c=3; Domain=example.com");
+		jar.set("www.wuxiaworld.com", "d=4; Domain=wuxiaworld.com");
+		assert_eq!(jar.header_for(&url("https://www.wuxiaworld.com/")).as_deref(), Some("d=4"));
+		assert_eq!(jar.header_for(&url("https://www.example.com/")), None);
+	}
+
+	#[test]
+	fn paths_match_on_a_slash() {
+		let jar = CookieJar::new();
+		jar.set("www.wuxiaworld.com", "a=1; Path=/novel");
+		let header = |path: &str| jar.header_for(&url(&format!("https://www.wuxiaworld.com{}", path)));
+		assert_eq!(header("/novel").as_deref(), Some("a=1"));
+		assert_eq!(header("/novel/foo").as_deref(), Some("a=1"));
+		assert_eq!(header("/novels"), None);
+		assert_eq!(header("/"), None);
+	}
+}
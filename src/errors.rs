@@ -0,0 +1,58 @@
+#![allow(unexpected_cfgs)]
+
+error_chain! {
+	foreign_links {
+		Io(::std::io::Error) #[doc = "Reading or writing a file failed, such as the cache or a saved page."];
+	}
+
+	errors {
+		/// A request couldn't be sent, or the server refused or failed to answer it.
+		NetworkError(message: String) {
+			description("network error")
+			display("{}", message)
+		}
+		/// A page isn't there, whether the server has nothing at its URL or it wasn't saved with `--offline`.
+		NotFound(message: String) {
+			description("not found")
+			display("{}", message)
+		}
+		/// A page didn't have what it was expected to, such as a book without its title or chapters.
+		ParseError(message: String) {
+			description("parse error")
+			display("{}", message)
+		}
+		/// A chapter's page was fetched, but nothing on it was the chapter.
+		NoContent(index: u32, title: String, url: String) {
+			description("chapter has no content")
+			display("Discovered no content for \"Chapter {} - {}\" (\"{}\")", index, title, url)
+		}
+		/// No adapter knows the site.
+		Unsupported(host: String) {
+			description("unsupported site")
+			display("Unsupported site \"{}\". Supported hosts: {}. To request support for another site, please open an issue.", host, crate::site::supported_hosts().join(", "))
+		}
+	}
+}
+
+impl Error {
+	/// The first kind along the chain that isn't a message added for context, so a failure can be told apart
+	/// however deeply it was wrapped. An error that's only ever messages is the outermost of them.
+	pub fn specific_kind(&self) -> &ErrorKind {
+		let mut error = self;
+		loop {
+			if !matches!(error.kind(), ErrorKind::Msg(_)) {
+				return error.kind();
+			}
+			match error.1.next_error.as_ref().and_then(|next| next.downcast_ref::<Error>()) {
+				Some(next) => error = next,
+				None => return self.kind(),
+			}
+		}
+	}
+
+	/// Whether the error is down to the one page it came from, such as the site failing to serve it or it missing
+	/// its content, rather than something that fails every other page too, like a file that can't be written.
+	pub fn is_recoverable(&self) -> bool {
+		matches!(self.specific_kind(), ErrorKind::NetworkError(_) | ErrorKind::NotFound(_) | ErrorKind::NoContent(..) | ErrorKind::ParseError(_))
+	}
+}
@@ -0,0 +1,1875 @@
+#![recursion_limit = "1024"]
+
+extern crate brotli_decompressor;
+extern crate chrono;
+extern crate dirs;
+extern crate encoding_rs;
+extern crate epub_builder;
+extern crate flate2;
+extern crate hyper;
+#[macro_use]
+extern crate error_chain;
+extern crate indicatif;
+#[macro_use]
+extern crate log;
+extern crate native_tls;
+extern crate regex;
+extern crate reqwest;
+extern crate select;
+extern crate serde;
+extern crate serde_json;
+extern crate sha2;
+extern crate url;
+extern crate uuid;
+extern crate xml;
+extern crate zip;
+
+use brotli_decompressor::Decompressor;
+use chrono::{DateTime, Utc};
+use encoding_rs::{Encoding, UTF_8};
+use flate2::read::ZlibDecoder;
+use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+use regex::{Captures, Regex};
+use reqwest::{Client, Response, StatusCode};
+use reqwest::header::{CONTENT_ENCODING, COOKIE, HeaderValue, RETRY_AFTER};
+use select::document::Document;
+use select::predicate::{Attr, Class, Name};
+use serde::{Serialize, Serializer};
+use sha2::{Digest, Sha256};
+use self::errors::*;
+use self::source::Source;
+use self::site::{SiteAdapter, normalize_volumes, synthesize_indices};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
+use std::error::Error as StdError;
+use std::fs::{read, read_to_string, write};
+use std::io::{self, Read};
+use std::iter;
+use std::path::{Path, PathBuf};
+use std::sync::{Condvar, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{Receiver, channel};
+use std::thread::{scope, sleep};
+use std::time::{Duration, Instant};
+use url::Url;
+
+pub mod config;
+pub mod cookies;
+pub mod errors;
+pub mod render;
+pub mod selector;
+pub mod site;
+pub mod source;
+pub mod update;
+pub mod validate;
+
+pub use self::render::{Compression, EpubOptions, book_identifier, build_epub, generate_cover, generate_epub, parse_meta, render_page_json, render_page_markdown, render_page_text, render_page_xhtml, pretty_xhtml, write_html, write_json, write_manifest, write_markdown, write_split, write_text};
+pub use self::config::{Config, SelectorConfig, load_config};
+pub use self::cookies::CookieJar;
+pub use self::site::{adapter_for, configure_selectors, supported_hosts};
+pub use self::source::{Fetched, HttpSource, OfflineSource};
+pub use self::update::{ExistingEpub, read_existing_epub};
+pub use self::validate::validate_epub;
+
+/// The delay before the first retry, doubling with every subsequent attempt.
+const RETRY_BASE_DELAY_MS: u64 = 500;
+/// The longest the doubling delay grows to, however many retries are allowed.
+const MAX_RETRY_DELAY_SECS: u64 = 60;
+/// The longest a server's `Retry-After` is honoured for, so a bogus value can't stall the download indefinitely.
+const MAX_RETRY_AFTER_SECS: u64 = 300;
+
+/// Replaces characters that are reserved on common filesystems, collapses whitespace and trims trailing dots/spaces.
+pub fn sanitize_filename(name: &str) -> String {
+	let replaced: String = name.chars()
+							   .map(|c| match c {
+								   '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => ' ',
+								   c if c.is_control() => ' ',
+								   c => c,
+							   })
+							   .collect();
+
+	let collapsed = replaced.split_whitespace()
+							.collect::<Vec<_>>()
+							.join(" ");
+
+	let trimmed = collapsed.trim_end_matches(['.', ' ']);
+	if trimmed.is_empty() {
+		"book".to_owned()
+	} else {
+		trimmed.to_owned()
+	}
+}
+
+/// Escapes the characters that are significant in XHTML text content and attribute values.
+///
+/// Scraped text comes from `Node::text()`, which the HTML parser has already entity-decoded, so a source
+/// `&amp;lt;` arrives here as `&lt;` and is escaped exactly once. Passing in text that still contains
+/// entities will escape them again.
+pub fn escape_html(text: &str) -> String {
+	let mut escaped = String::with_capacity(text.len());
+	for c in text.chars() {
+		match c {
+			'&' => escaped.push_str("&amp;"),
+			'<' => escaped.push_str("&lt;"),
+			'>' => escaped.push_str("&gt;"),
+			'"' => escaped.push_str("&quot;"),
+			'\'' => escaped.push_str("&#39;"),
+			c => escaped.push(c),
+		}
+	}
+	escaped
+}
+
+/// Derives a directory name for the book's resume cache from its URL.
+pub fn cache_key(url: &Url) -> String {
+	let key = format!("{}{}", url.host_str().unwrap_or(""), url.path());
+	key.trim_end_matches('/')
+	   .chars()
+	   .map(|c| if c.is_ascii_alphanumeric() || c == '-' { c } else { '_' })
+	   .collect()
+}
+
+/// Upper bound on the table of contents pages followed for a single book.
+const MAX_TOC_PAGES: usize = 500;
+
+/// Fetches the book page at `url` and parses it with the adapter for its host, following any pagination
+/// of the chapter list. The page being fetched and the chapters found so far are shown on `bar`.
+pub fn fetch_book_info(source: &dyn Source, url: Url, fetch: &FetchOptions, bar: &ProgressBar) -> Result<BookInfo> {
+	let adapter = adapter_for(&url)?;
+
+	debug!("Using the {} adapter", adapter.hosts()[0]);
+	bar.set_message("Fetching book page");
+	let (doc, url, mut info) = fetch_book_page(source, adapter, url, fetch)?;
+
+	let mut visited: HashSet<Url> = HashSet::new();
+	visited.insert(url.clone());
+	let mut queue: VecDeque<Url> = adapter.toc_pages(&doc, &url).into_iter().collect();
+	let mut links: HashSet<Url> = info.chapters.iter().map(|chapter| chapter.link.clone()).collect();
+
+	while let Some(page) = queue.pop_front() {
+		if visited.len() >= MAX_TOC_PAGES {
+			warn!("Stopped following the table of contents after {} pages.", MAX_TOC_PAGES);
+			break;
+		}
+		if !visited.insert(page.clone()) {
+			continue;
+		}
+
+		debug!("Following table of contents page \"{}\"", page);
+		bar.set_message(format!("Fetching table of contents page {} ({} chapters so far)", visited.len(), info.chapters.len()));
+		let (doc, page) = fetch_document(source, page.clone(), fetch)
+			.chain_err(|| format!("Unable to fetch table of contents page \"{}\".", page))?;
+		visited.insert(page.clone());
+
+		for chapter in adapter.parse_chapter_list(&doc, &page)? {
+			// Overlapping pages list some chapters more than once.
+			if links.insert(chapter.link.clone()) {
+				info.chapters.push(chapter);
+			}
+		}
+		queue.extend(adapter.toc_pages(&doc, &page).into_iter().filter(|url| !visited.contains(url)));
+	}
+
+	if info.chapters.is_empty() {
+		bail!(ErrorKind::ParseError(format!("Found no chapters for \"{}\" at \"{}\". The site may have served a challenge page (like Cloudflare's) instead of the book, or changed its markup so the selectors no longer match; run with \"-v\" to see what was received.", info.title, url)));
+	}
+
+	normalize_volumes(&mut info.chapters);
+	synthesize_indices(&mut info.chapters);
+
+	Ok(info)
+}
+
+/// Fetches and parses the book page, fetching it again (up to `FetchOptions::retries` times, like a chapter) when
+/// it doesn't parse or lists no chapters and links to no more of them, which a challenge page served in its
+/// place (or a transient error page) looks like.
+///
+/// A chapter given in place of the book is followed to the book it links to.
+fn fetch_book_page(source: &dyn Source, adapter: &dyn SiteAdapter, mut url: Url, fetch: &FetchOptions) -> Result<(Document, Url, BookInfo)> {
+	let mut attempt = 0;
+	let mut from_chapter = false;
+	loop {
+		let (doc, served) = fetch_document(source, url.clone(), fetch)
+			.chain_err(|| "Unable to fetch book page.")?;
+		// Relative links are resolved against where the page was eventually served from.
+		if served != url {
+			info!("\"{}\" redirected to \"{}\"", url, served);
+		}
+		debug!("Resolving links against \"{}\"", served);
+
+		let result = adapter.parse_book_info(&doc, &served);
+		let listed = match result {
+			Ok(ref info) => !info.chapters.is_empty() || !adapter.toc_pages(&doc, &served).is_empty(),
+			Err(_) => false,
+		};
+		if listed {
+			return Ok((doc, served, result?));
+		}
+		debug!("Received from \"{}\": {}", served, html_snippet(&doc));
+		// Asking again only gets the same challenge.
+		if let Some(challenge) = detect_challenge(&doc) {
+			return Err(challenge_error(&served, challenge));
+		}
+		if !from_chapter && adapter.is_chapter_page(&doc) {
+			match adapter.book_page(&doc, &served) {
+				Some(book) => {
+					info!("\"{}\" is a chapter, so its book is fetched from \"{}\" instead.", served, book);
+					url = book;
+					from_chapter = true;
+					attempt = 0;
+					continue;
+				}
+				// A page with the text of a chapter isn't going to list any chapters when fetched again.
+				None if text_length(&adapter.parse_chapter(&doc, &served)?)? >= fetch.min_content_chars => {
+					bail!("\"{}\" looks like a chapter rather than a book, and doesn't link back to its table of contents. Give the book's own page instead, the one listing its chapters.", served);
+				}
+				None => {}
+			}
+		}
+
+		let problem = match result {
+			// Left for `fetch_book_info` to explain.
+			Ok(info) if attempt >= fetch.retries => return Ok((doc, served, info)),
+			Ok(_) => Error::from(ErrorKind::ParseError("No chapters were listed.".to_owned())),
+			Err(e) if attempt >= fetch.retries => return Err(e),
+			Err(e) => e,
+		};
+		let delay = retry_delay(attempt);
+		warn!("The book page didn't parse, retrying in {:?}: {}", delay, problem);
+		sleep(delay);
+		attempt += 1;
+	}
+}
+
+/// Names the anti-bot challenge (or block page) `doc` is, if it's one served in place of the page asked for.
+///
+/// Only the markers of the challenges themselves are looked for, as Cloudflare also injects its scripts into
+/// the pages it lets through.
+fn detect_challenge(doc: &Document) -> Option<&'static str> {
+	let title = doc.find(Name("title"))
+				   .next()
+				   .map(|title| title.text().split_whitespace().collect::<Vec<_>>().join(" ").to_lowercase())
+				   .unwrap_or_default();
+	let scripts = || doc.find(Name("script"));
+	let has_id = |id| doc.find(Attr("id", id)).next().is_some();
+
+	if title.starts_with("just a moment") || has_id("challenge-form") || has_id("cf-challenge-running")
+		|| scripts().any(|script| script.text().contains("_cf_chl_opt")) {
+		Some("Cloudflare challenge")
+	} else if doc.find(Class("cf-turnstile")).next().is_some() {
+		Some("Cloudflare Turnstile challenge")
+	} else if title.contains("attention required") && title.contains("cloudflare") {
+		Some("Cloudflare block page")
+	} else if title.contains("ddos-guard") || scripts().any(|script| script.attr("src").is_some_and(|src| src.contains("ddos-guard"))) {
+		Some("DDoS-Guard challenge")
+	} else if title.contains("sucuri website firewall") {
+		Some("Sucuri firewall page")
+	} else {
+		None
+	}
+}
+
+/// Explains that `url` served a `challenge` (see `detect_challenge`) instead of the page.
+fn challenge_error(url: &Url, challenge: &str) -> Error {
+	ErrorKind::NetworkError(format!("\"{}\" served a {} instead of the page, which can't be solved automatically. Open the page in a browser to get past it, then pass that browser's cookies with \"--cookie\" or \"--cookie-file\", along with its \"--user-agent\".", url, challenge)).into()
+}
+
+/// How many characters of a page are logged when it doesn't parse.
+const SNIPPET_CHARS: usize = 500;
+
+/// The start of the markup of `doc`, on one line.
+fn html_snippet(doc: &Document) -> String {
+	let html = doc.find(Name("body"))
+				  .next()
+				  .or_else(|| doc.nth(0))
+				  .map(|node| node.html())
+				  .unwrap_or_default();
+	let html = html.split_whitespace().collect::<Vec<_>>().join(" ");
+	match html.char_indices().nth(SNIPPET_CHARS) {
+		Some((end, _)) => format!("{}…", &html[..end]),
+		None => html,
+	}
+}
+
+/// Fetches and parses the page at `url`, returning it along with the URL it was eventually served from.
+fn fetch_document(source: &dyn Source, url: Url, fetch: &FetchOptions) -> Result<(Document, Url)> {
+	let fetched = source.get(&url, fetch)?;
+
+	let doc = read_document(fetched.body, fetched.charset.as_deref(), &fetched.url)
+		.chain_err(|| "Unable to construct document from response.")?;
+
+	Ok((doc, fetched.url))
+}
+
+/// How far into a page a `<meta>` tag declaring its charset is looked for, as browsers do.
+const META_CHARSET_BYTES: usize = 1024;
+
+/// Reads and parses a page, decoded to UTF-8 from whichever charset it's really in.
+///
+/// Sites translating from Chinese often declare the wrong charset, or none at all, so it isn't taken on faith: a
+/// byte order mark wins, then UTF-8 if the page is valid UTF-8 (which text in any other charset hardly ever is),
+/// then `charset` from the `Content-Type` header, then a `<meta>` tag's. The first of those the page decodes
+/// cleanly in is used, falling back to the declared charset (or UTF-8) with the broken bytes replaced.
+fn read_document(mut body: Box<dyn Read>, charset: Option<&str>, url: &Url) -> io::Result<Document> {
+	let mut bytes = Vec::new();
+	body.read_to_end(&mut bytes)?;
+	Ok(Document::from(decode_html(&bytes, charset, url).as_str()))
+}
+
+fn decode_html(bytes: &[u8], charset: Option<&str>, url: &Url) -> String {
+	if let Some((encoding, bom)) = Encoding::for_bom(bytes) {
+		return encoding.decode_without_bom_handling(&bytes[bom..]).0.into_owned();
+	}
+	if let Ok(text) = std::str::from_utf8(bytes) {
+		return text.to_owned();
+	}
+
+	let header = charset.and_then(|label| Encoding::for_label(label.as_bytes()));
+	let meta = meta_charset(bytes);
+	for encoding in [header, meta].iter().flatten().filter(|encoding| **encoding != UTF_8) {
+		if let Some(text) = encoding.decode_without_bom_handling_and_without_replacement(bytes) {
+			if header.is_some_and(|header| header != *encoding) {
+				debug!("\"{}\" isn't {} as it's served, but {}", url, header.unwrap().name(), encoding.name());
+			}
+			return text.into_owned();
+		}
+	}
+
+	let encoding = header.or(meta).unwrap_or(UTF_8);
+	warn!("\"{}\" isn't valid {}, so some of its characters will be missing.", url, encoding.name());
+	encoding.decode_without_bom_handling(bytes).0.into_owned()
+}
+
+/// The charset a `<meta charset>` or `<meta http-equiv="Content-Type">` tag near the start of the page declares.
+fn meta_charset(bytes: &[u8]) -> Option<&'static Encoding> {
+	let head = String::from_utf8_lossy(&bytes[..bytes.len().min(META_CHARSET_BYTES)]).to_ascii_lowercase();
+	head.split("<meta")
+		.skip(1)
+		.find_map(|tag| {
+			let tag = &tag[..tag.find('>').unwrap_or(tag.len())];
+			let start = tag.find("charset=")? + "charset=".len();
+			let label = tag[start..].trim_start_matches(['"', '\''])
+									.split(|c: char| c == '"' || c == '\'' || c == ';' || c == '/' || c.is_whitespace())
+									.next()?;
+			Encoding::for_label(label.as_bytes())
+		})
+		// A page that could declare itself UTF-16 in ASCII isn't UTF-16.
+		.map(Encoding::output_encoding)
+}
+
+/// What's sent as `Accept-Encoding`, all of which `decode_body` can decode.
+pub const ACCEPT_ENCODINGS: &str = "gzip, deflate, br";
+
+/// The body of `res`, decoded according to its `Content-Encoding`.
+///
+/// Gzip is already decoded by the client (which drops the header), leaving deflate and brotli. Anything else is
+/// an error, rather than parsing what would only be binary noise.
+pub(crate) fn decode_body(res: Response) -> Result<Box<dyn Read>> {
+	let encoding = res.headers()
+					  .get(CONTENT_ENCODING)
+					  .and_then(|value| value.to_str().ok())
+					  .map(|value| value.trim().to_ascii_lowercase());
+
+	match encoding.as_deref() {
+		None | Some("") | Some("identity") => Ok(Box::new(res)),
+		Some("deflate") => Ok(Box::new(ZlibDecoder::new(res))),
+		Some("br") => Ok(Box::new(Decompressor::new(res, 4096))),
+		Some(encoding) => bail!(ErrorKind::NetworkError(format!("Unable to decode the response from \"{}\", as it's encoded with \"{}\".", res.url(), encoding))),
+	}
+}
+
+/// Sends a GET request to `url` with the cookies in `cookies`, retrying failed requests and non-2xx responses
+/// with exponential backoff.
+///
+/// Throttled responses (429 and 503) wait for as long as their `Retry-After` header asks instead, but still
+/// count towards the retry limit. A 404 isn't retried at all, failing with `ErrorKind::NotFound`.
+pub(crate) fn execute_with_retry(client: &Client, cookies: &CookieJar, url: &Url, fetch: &FetchOptions) -> Result<Response> {
+	let mut attempt = 0;
+	loop {
+		fetch.rate_limiter.wait();
+
+		let mut req = client.get(url.clone())
+							.build()
+							.chain_err(|| "Unable to construct request.")?;
+		if let Some(header) = cookies.header_for(url) {
+			let value = HeaderValue::from_str(&header)
+				.chain_err(|| "Invalid cookies: they can't be sent in a header.")?;
+			req.headers_mut().insert(COOKIE, value);
+		}
+		let result = client.execute(req);
+		if let Ok(ref res) = result {
+			cookies.store(res);
+		}
+
+		let (error, delay) = match result {
+			Ok(res) if res.status().is_success() => return Ok(res),
+			// Asking again won't change the answer.
+			// Cloudflare marks the challenges it answers with, whatever their status.
+			Ok(ref res) if res.headers().get("cf-mitigated").is_some_and(|value| value == "challenge") => {
+				return Err(challenge_error(url, "Cloudflare challenge"));
+			}
+			Ok(ref res) if matches!(res.status(), StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN) => {
+				bail!(ErrorKind::NetworkError(format!("Not authorized to read \"{}\" (the server responded with \"{}\"). Subscriber-only chapters need credentials, given with \"--auth-bearer\" or \"--auth-basic\".", url, res.status())));
+			}
+			Ok(ref res) if res.status() == StatusCode::NOT_FOUND => {
+				bail!(ErrorKind::NotFound(format!("Nothing was found at \"{}\" (the server responded with \"{}\").", url, res.status())));
+			}
+			Ok(res) => {
+				let delay = match res.status() {
+					StatusCode::TOO_MANY_REQUESTS | StatusCode::SERVICE_UNAVAILABLE => retry_after(&res),
+					_ => None,
+				};
+				(Error::from(ErrorKind::NetworkError(format!("Server responded with \"{}\".", res.status()))), delay)
+			}
+			// Neither will a certificate that can't be trusted.
+			Err(e) if is_tls_error(&e) => {
+				return Err(Error::with_chain(e, ErrorKind::NetworkError(format!("Unable to make a secure connection to \"{}\". If its certificate is expired or self-signed and you trust it anyway, \"--danger-accept-invalid-certs\" skips checking it.", url.host_str().unwrap_or_default()))));
+			}
+			Err(e) => {
+				let timeout = timeout_kind(&e);
+				(send_error(e, url, timeout, fetch.proxy.as_ref()), None)
+			}
+		};
+
+		if attempt >= fetch.retries {
+			return Err(error).chain_err(|| format!("Giving up after {} attempts.", attempt + 1));
+		}
+
+		let delay = delay.unwrap_or_else(|| retry_delay(attempt));
+		warn!("Request to \"{}\" failed, retrying in {:?}: {}", url, delay, error);
+		sleep(delay);
+		attempt += 1;
+	}
+}
+
+/// Which stage of a request timed out.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum TimeoutKind {
+	/// The host couldn't be connected to in time.
+	Connect,
+	/// The server took too long to respond once connected.
+	Read,
+}
+
+fn timeout_kind(error: &reqwest::Error) -> Option<TimeoutKind> {
+	if !error.is_timeout() {
+		return None;
+	}
+
+	// reqwest only tells the two apart through the message of the underlying IO error.
+	let mut source: Option<&(dyn StdError + 'static)> = Some(error);
+	while let Some(error) = source {
+		if error.to_string() == "connect timed out" {
+			return Some(TimeoutKind::Connect);
+		}
+		source = error.source();
+	}
+	Some(TimeoutKind::Read)
+}
+
+/// The errors underneath a failed request, outermost first.
+///
+/// reqwest's own error is left out, as its message leads with the URL, which says nothing about what went wrong.
+fn causes(error: &reqwest::Error) -> impl Iterator<Item = &(dyn StdError + 'static)> {
+	let first = error.get_ref().map(|error| error as &(dyn StdError + 'static));
+	iter::successors(first, |&error| match error.downcast_ref::<io::Error>().and_then(io::Error::get_ref) {
+		// The connector wraps its errors in IO errors, which pass them off as their message rather than their source.
+		Some(inner) => Some(inner as &(dyn StdError + 'static)),
+		None => error.source(),
+	})
+}
+
+/// Whether the request never made it past establishing a connection (or a tunnel through a proxy).
+fn is_connect_error(error: &reqwest::Error) -> bool {
+	causes(error).any(|cause| cause.downcast_ref::<hyper::Error>().is_some_and(hyper::Error::is_connect))
+}
+
+/// Whether the request failed to set up TLS, usually over the site's certificate.
+fn is_tls_error(error: &reqwest::Error) -> bool {
+	causes(error).any(|cause| cause.is::<native_tls::Error>())
+}
+
+/// Wraps a request that failed to send, spelling out which timeout it hit (if any), and whether it was the
+/// proxy rather than the site that couldn't be reached.
+fn send_error(error: reqwest::Error, url: &Url, timeout: Option<TimeoutKind>, proxy: Option<&Url>) -> Error {
+	let host = url.host_str().unwrap_or_default();
+	let message = match (timeout, proxy) {
+		(Some(TimeoutKind::Connect), Some(proxy)) => format!("Timed out connecting to the proxy \"{}\".", proxy),
+		(Some(TimeoutKind::Connect), None) => format!("Timed out connecting to \"{}\".", host),
+		(Some(TimeoutKind::Read), _) => format!("Timed out waiting for a response from \"{}\".", url),
+		(None, Some(proxy)) if is_connect_error(&error) => format!("Unable to reach \"{}\" through the proxy \"{}\".", host, proxy),
+		(None, _) => "Unable to send request.".to_owned(),
+	};
+	Error::with_chain(error, ErrorKind::NetworkError(message))
+}
+
+/// How long to wait before retrying after `attempt` failed attempts, doubling every time up to `MAX_RETRY_DELAY_SECS`.
+fn retry_delay(attempt: u32) -> Duration {
+	let delay = 2u64.checked_pow(attempt)
+					.map_or(u64::MAX, |factor| factor.saturating_mul(RETRY_BASE_DELAY_MS));
+	Duration::from_millis(delay).min(Duration::from_secs(MAX_RETRY_DELAY_SECS))
+}
+
+/// Reads the `Retry-After` header, in either its delay-seconds or HTTP-date form.
+fn retry_after(res: &Response) -> Option<Duration> {
+	let value = res.headers()
+				   .get(RETRY_AFTER)?
+				   .to_str()
+				   .ok()?
+				   .trim();
+
+	let delay = match value.parse::<u64>() {
+		Ok(seconds) => Duration::from_secs(seconds),
+		Err(_) => {
+			let date = DateTime::parse_from_rfc2822(value).ok()?;
+			(date.with_timezone(&Utc) - Utc::now()).to_std().unwrap_or_default()
+		}
+	};
+
+	Some(delay.min(Duration::from_secs(MAX_RETRY_AFTER_SECS)))
+}
+
+/// A downloaded image, such as the cover or an illustration from a chapter.
+pub struct Image {
+	pub data: Vec<u8>,
+	pub mime: String,
+}
+
+/// Downloads an image, inferring its mime type from the response or the file extension.
+pub fn fetch_image(source: &dyn Source, url: &Url, fetch: &FetchOptions) -> Result<Image> {
+	let mut fetched = source.get(url, fetch)?;
+
+	let mime = fetched.mime
+					  .take()
+					  .filter(|value| value.starts_with("image/"))
+					  .or_else(|| image_mime_from_path(url.path()).map(str::to_owned))
+					  .chain_err(|| format!("Unable to determine the image type of \"{}\"", url))?;
+
+	let mut bytes = Vec::new();
+	fetched.body.read_to_end(&mut bytes)
+		   .chain_err(|| "Unable to read image.")?;
+
+	Ok(Image {
+		data: bytes,
+		mime,
+	})
+}
+
+/// Reads an image from disk, inferring its mime type from the file extension.
+pub fn read_image(path: &Path) -> Result<Image> {
+	let mime = path.to_str()
+				   .and_then(image_mime_from_path)
+				   .chain_err(|| format!("Unable to determine the image type of \"{}\"", path.display()))?;
+	let data = read(path)
+		.map_err(Error::from)
+		.chain_err(|| format!("Unable to read image: \"{}\"", path.display()))?;
+
+	Ok(Image {
+		data,
+		mime: mime.to_owned(),
+	})
+}
+
+pub(crate) fn image_mime_from_path(path: &str) -> Option<&'static str> {
+	let extension = path.rsplit('.').next()?.to_lowercase();
+	let mime = match extension.as_str() {
+		"jpg" | "jpeg" => "image/jpeg",
+		"png" => "image/png",
+		"gif" => "image/gif",
+		"webp" => "image/webp",
+		"svg" => "image/svg+xml",
+		_ => return None,
+	};
+	Some(mime)
+}
+
+pub fn image_extension(mime: &str) -> &str {
+	match mime {
+		"image/jpeg" => "jpg",
+		"image/svg+xml" => "svg",
+		mime => mime.trim_start_matches("image/"),
+	}
+}
+
+/// Collects the images referenced by chapters, so an image shared between chapters is only embedded once.
+#[derive(Default)]
+pub struct ImageStore {
+	paths: Mutex<HashMap<String, Option<String>>>,
+	images: Mutex<Vec<(String, Image)>>,
+}
+
+impl ImageStore {
+	pub fn new() -> ImageStore {
+		ImageStore::default()
+	}
+
+	/// Starts the store off with images embedded by an earlier run, numbering new ones after them.
+	pub fn with_images(images: Vec<(String, Image)>) -> ImageStore {
+		ImageStore {
+			paths: Mutex::new(HashMap::new()),
+			images: Mutex::new(images),
+		}
+	}
+
+	/// Returns the resource path of the image at `url`, downloading it if this is the first time it's been seen.
+	/// Failed downloads are remembered, so they are only attempted once.
+	fn resolve(&self, source: &dyn Source, url: &Url, fetch: &FetchOptions) -> Option<String> {
+		if let Some(path) = self.paths.lock().unwrap().get(url.as_str()) {
+			return path.clone();
+		}
+
+		let image = fetch_image(source, url, fetch);
+
+		let mut paths = self.paths.lock().unwrap();
+		if let Some(path) = paths.get(url.as_str()) {
+			return path.clone();
+		}
+		let path = match image {
+			Ok(image) => {
+				let mut images = self.images.lock().unwrap();
+				let path = format!("images/image_{}.{}", images.len(), image_extension(&image.mime));
+				images.push((path.clone(), image));
+				Some(path)
+			}
+			Err(e) => {
+				warn!("Unable to fetch image \"{}\", dropping it: {}", url, e);
+				None
+			}
+		};
+		paths.insert(url.as_str().to_owned(), path.clone());
+		path
+	}
+
+	/// Takes every successfully downloaded image out of the store, along with its resource path.
+	pub fn take_images(&self) -> Vec<(String, Image)> {
+		::std::mem::take(&mut *self.images.lock().unwrap())
+	}
+}
+
+/// Downloads the images referenced by `content` and points them at their embedded copies, dropping any that failed.
+fn localize_images(source: &dyn Source, content: &str, images: &ImageStore, fetch: &FetchOptions) -> Result<String> {
+	let image_regex = Regex::new(r#"<div class="image"><img src="([^"]*)" alt=""/></div>\n?"#)
+		.chain_err(|| "Unable to construct regex.")?;
+
+	let localized = image_regex.replace_all(content, |cap: &Captures| {
+		let src = cap[1].replace("&amp;", "&");
+		let path = Url::parse(&src)
+			.ok()
+			.and_then(|url| images.resolve(source, &url, fetch));
+		match path {
+			Some(path) => format!("<div class=\"image\"><img src=\"{}\" alt=\"\"/></div>\n", path),
+			None => String::new(),
+		}
+	});
+
+	Ok(localized.into_owned())
+}
+
+/// Removes the inline formatting `SiteAdapter::parse_chapter` keeps from the source, leaving just the text.
+fn strip_inline_formatting(content: &str) -> Result<String> {
+	let formatting_regex = Regex::new(r#"</?(?:strong|em)>|<span class="underline">|</span>"#)
+		.chain_err(|| "Unable to construct regex.")?;
+	Ok(formatting_regex.replace_all(content, "").into_owned())
+}
+
+/// Which way `--normalize-punct` turns the punctuation of the content.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Punctuation {
+	/// Curly quotes, dashes and ellipses become their plain ASCII stand-ins.
+	Ascii,
+	/// Straight quotes, double hyphens and runs of three dots become curly quotes, em dashes and ellipses.
+	Typographic,
+}
+
+/// Rewrites the punctuation in the text of `content` as `punctuation` says, leaving its markup alone.
+fn normalize_punctuation(content: &str, punctuation: Punctuation) -> String {
+	let mut normalized = String::with_capacity(content.len());
+	// Whether a quote opens or closes depends on what comes before it, which carries on across inline tags.
+	let mut previous = '\n';
+	let mut rest = content;
+	while !rest.is_empty() {
+		let end = if rest.starts_with('<') {
+			let end = rest.find('>').map_or(rest.len(), |i| i + 1);
+			normalized.push_str(&rest[..end]);
+			end
+		} else {
+			let end = rest.find('<').unwrap_or(rest.len());
+			match punctuation {
+				Punctuation::Ascii => push_ascii_punctuation(&mut normalized, &rest[..end]),
+				Punctuation::Typographic => push_typographic_punctuation(&mut normalized, &rest[..end], &mut previous),
+			}
+			end
+		};
+		rest = &rest[end..];
+	}
+	normalized
+}
+
+fn push_ascii_punctuation(normalized: &mut String, text: &str) {
+	for c in text.chars() {
+		match c {
+			'\u{201C}' | '\u{201D}' | '\u{201E}' | '\u{201F}' => normalized.push_str("&quot;"),
+			'\u{2018}' | '\u{2019}' | '\u{201A}' | '\u{201B}' => normalized.push_str("&#39;"),
+			'\u{2026}' => normalized.push_str("..."),
+			'\u{2014}' | '\u{2015}' => normalized.push_str("--"),
+			'\u{2010}' | '\u{2011}' | '\u{2012}' | '\u{2013}' | '\u{2212}' => normalized.push('-'),
+			c => normalized.push(c),
+		}
+	}
+}
+
+/// Text is escaped with `escape_html`, so the straight quotes come as entities.
+fn push_typographic_punctuation(normalized: &mut String, text: &str, previous: &mut char) {
+	// A quote after a space, the start of a paragraph or an opening bracket opens, while any other closes.
+	let opens = |previous: char| previous.is_whitespace() || matches!(previous, '(' | '[' | '{' | '\u{2014}' | '\u{2013}' | '\u{201C}' | '\u{2018}');
+
+	let mut rest = text;
+	while let Some(c) = rest.chars().next() {
+		let (replacement, length) = if rest.starts_with("&quot;") {
+			(if opens(*previous) { '\u{201C}' } else { '\u{201D}' }, "&quot;".len())
+		} else if rest.starts_with("&#39;") {
+			(if opens(*previous) { '\u{2018}' } else { '\u{2019}' }, "&#39;".len())
+		} else if rest.starts_with("...") {
+			('\u{2026}', 3)
+		} else if rest.starts_with("---") {
+			('\u{2014}', 3)
+		} else if rest.starts_with("--") {
+			('\u{2014}', 2)
+		} else {
+			(c, c.len_utf8())
+		};
+		normalized.push(replacement);
+		*previous = replacement;
+		rest = &rest[length..];
+	}
+}
+
+/// Settings shared by every chapter fetch.
+pub struct FetchOptions {
+	pub retries: u32,
+	pub cache_dir: Option<PathBuf>,
+	pub rate_limiter: RateLimiter,
+	/// The proxy every request goes through, if any, so its failures can be told apart from the site's.
+	pub proxy: Option<Url>,
+	/// Chapter content salvaged from an existing epub, by file stem, used instead of fetching it again.
+	pub existing: HashMap<String, String>,
+	/// Whether to flatten the bold, italic and underlined text kept from the source.
+	pub plain: bool,
+	/// Which way the punctuation of the content is normalized, if at all, see `normalize_punctuation`.
+	pub punctuation: Option<Punctuation>,
+	/// Whether a chapter that can't be fetched is replaced by a placeholder, see `placeholder_page`, rather
+	/// than failing the whole book, so long as the failure is the chapter's own and not, say, the cache's.
+	pub skip_failed: bool,
+	/// Chapters with less text than this are fetched again, unless they're in the markup chapters are found in.
+	pub min_content_chars: usize,
+	/// Set to stop fetching chapters (after the ones already underway), say once the user has hit Ctrl-C.
+	pub interrupted: &'static AtomicBool,
+	/// After how many chapters failing in a row the whole book is given up on, even with `skip_failed`.
+	pub abort_after: Option<usize>,
+	/// How many chapters have failed in a row so far, see `abort_after`.
+	pub consecutive_failures: AtomicUsize,
+	/// The index of every chapter replaced by a placeholder, along with why it couldn't be fetched.
+	pub failed: Mutex<Vec<(u32, String)>>,
+	/// What chapters are headed with, see `Chapter::page_title`.
+	pub title_format: String,
+	/// Chapters longer than this many bytes are truncated, as the selectors likely caught more than the chapter.
+	pub max_chapter_bytes: Option<usize>,
+	/// How many words the chapters fetched so far have between them.
+	pub words: AtomicUsize,
+	/// The index of every chapter fetched so far, along with how many words it has, for telling stubs apart.
+	pub chapter_words: Mutex<Vec<(u32, usize)>>,
+	/// Where each chapter being downloaded gets a bar of its own, counting off its bytes, see `download_bar`.
+	pub downloads: Option<MultiProgress>,
+}
+
+/// Spaces out requests across all threads, so the aggregate rate never exceeds one request per `interval`.
+pub struct RateLimiter {
+	interval: Duration,
+	next: Mutex<Instant>,
+}
+
+impl RateLimiter {
+	pub fn new(interval: Duration) -> RateLimiter {
+		RateLimiter {
+			interval,
+			next: Mutex::new(Instant::now()),
+		}
+	}
+
+	/// Blocks until the calling thread may send its next request.
+	pub fn wait(&self) {
+		if self.interval == Duration::from_millis(0) {
+			return;
+		}
+
+		let slot = {
+			let mut next = self.next.lock().unwrap();
+			let slot = (*next).max(Instant::now());
+			*next = slot + self.interval;
+			slot
+		};
+
+		let now = Instant::now();
+		if slot > now {
+			sleep(slot - now);
+		}
+	}
+}
+
+/// Fetches a chapter (or reads it from the cache), embedding its images into `images` when given.
+/// Without a store the image placeholders are left untouched.
+///
+/// The page is named with its index padded to `width` digits, see `index_width`.
+pub fn fetch_chapter_content(source: &dyn Source, chapter: &Chapter, fetch: &FetchOptions, images: Option<&ImageStore>, bar: &ProgressBar, width: usize) -> Result<Page> {
+	bar.set_message(chapter.title.clone());
+
+	let name = chapter.file_stem();
+	let cache_path = fetch.cache_dir.as_ref().map(|dir| dir.join(format!("{}.xhtml", name)));
+
+	let content = match fetch.existing.get(&name) {
+		// Its images are already embedded, so it's used as is.
+		Some(content) => {
+			debug!("Reused chapter {} from the existing epub", chapter.index);
+			content.clone()
+		}
+		None => {
+			let cached = cache_path.as_ref()
+								   .and_then(|path| read_to_string(path).ok())
+								   .filter(|content| !content.is_empty());
+
+			let content = match cached {
+				Some(content) => {
+					debug!("Read chapter {} from the cache", chapter.index);
+					content
+				}
+				None => {
+					debug!("Fetching chapter {} from \"{}\"", chapter.index, chapter.link);
+					let content = download_chapter_content(source, chapter, fetch)?;
+					if let Some(ref path) = cache_path {
+						write(path, &content)
+							.map_err(Error::from)
+							.chain_err(|| format!("Unable to write chapter to cache: \"{}\"", path.display()))?;
+					}
+					content
+				}
+			};
+
+			match images {
+				Some(images) => localize_images(source, &content, images, fetch)?,
+				None => content,
+			}
+		}
+	};
+	// Done after caching, so the cache holds the formatting either way.
+	let content = if fetch.plain {
+		strip_inline_formatting(&content)?
+	} else {
+		content
+	};
+	let content = match fetch.punctuation {
+		Some(punctuation) => normalize_punctuation(&content, punctuation),
+		None => content,
+	};
+	let content = match fetch.max_chapter_bytes {
+		Some(limit) if content.len() > limit => {
+			warn!("Chapter {} is {} bytes long, over the limit of {}, so it's been truncated. The content selectors may have matched the rest of the page.", chapter.index, content.len(), limit);
+			truncate_content(content, limit)
+		}
+		_ => content,
+	};
+
+	let words = word_count(&content)?;
+	debug!("Chapter {} has {} words", chapter.index, words);
+	fetch.words.fetch_add(words, Ordering::SeqCst);
+	fetch.chapter_words.lock().unwrap().push((chapter.index, words));
+
+	bar.inc(1);
+
+	Ok(Page {
+		index: chapter.index,
+		name: chapter.padded_file_stem(width),
+		title: chapter.page_title(&fetch.title_format),
+		volume: chapter.volume.clone(),
+		sha256: content_digest(&content),
+		content,
+		link: chapter.link.clone(),
+		words,
+	})
+}
+
+/// Upper bound on the pages a single chapter is followed across.
+const MAX_CHAPTER_PAGES: usize = 10;
+
+/// Fetches and parses a chapter, following it onto the next page for as long as it's split across several
+/// (up to `MAX_CHAPTER_PAGES`), and joining their content into one.
+fn download_chapter_content(source: &dyn Source, chapter: &Chapter, fetch: &FetchOptions) -> Result<String> {
+	let adapter = adapter_for(&chapter.link)?;
+	let (mut content, mut next) = download_chapter_page(source, adapter, chapter, &chapter.link, fetch)?;
+
+	let mut visited = vec![chapter.link.clone()];
+	while let Some(url) = next.take() {
+		// A link back to a page already fetched would only go round in circles.
+		if visited.contains(&url) {
+			debug!("Chapter {} links back to \"{}\", which was already fetched", chapter.index, url);
+			break;
+		}
+		if visited.len() >= MAX_CHAPTER_PAGES {
+			warn!("Chapter {} goes on for more than {} pages, only the first {} were fetched.", chapter.index, MAX_CHAPTER_PAGES, MAX_CHAPTER_PAGES);
+			break;
+		}
+
+		debug!("Fetching page {} of chapter {} from \"{}\"", visited.len() + 1, chapter.index, url);
+		let (page, page_next) = download_chapter_page(source, adapter, chapter, &url, fetch)?;
+		content.push_str(&page);
+		visited.push(url);
+		next = page_next;
+	}
+	Ok(content)
+}
+
+/// Fetches and parses a page of a chapter, fetching it again (up to `FetchOptions::retries` times) when what came
+/// back looks like a truncated or error page instead: too short, and without the markup chapters are found in.
+///
+/// Returns the page's content along with the page the chapter continues on, if it does.
+fn download_chapter_page(source: &dyn Source, adapter: &dyn SiteAdapter, chapter: &Chapter, url: &Url, fetch: &FetchOptions) -> Result<(String, Option<Url>)> {
+	let mut attempt = 0;
+	loop {
+		let fetched = match source.get(url, fetch) {
+			Ok(fetched) => fetched,
+			// Nothing there is no different from nothing on the page.
+			Err(e) if matches!(e.specific_kind(), ErrorKind::NotFound(_)) => {
+				return Err(Error::with_chain(e, ErrorKind::NoContent(chapter.index, chapter.title.clone(), url.to_string())));
+			}
+			Err(e) => return Err(e).chain_err(|| format!("Unable to fetch \"Chapter {}\" from \"{}\"", chapter.index, url)),
+		};
+
+		let download = download_bar(fetch, chapter, fetched.length)?;
+		let body: Box<dyn Read> = match download {
+			Some((_, ref bar)) => Box::new(bar.wrap_read(fetched.body)),
+			None => fetched.body,
+		};
+		let doc = read_document(body, fetched.charset.as_deref(), &fetched.url);
+		if let Some((downloads, bar)) = download {
+			bar.finish_and_clear();
+			downloads.remove(&bar);
+		}
+		let doc: Document = doc
+			.map_err(|e| match e.kind() {
+				io::ErrorKind::TimedOut => Error::with_chain(e, ErrorKind::NetworkError(format!("Timed out reading the response from \"{}\".", url))),
+				_ => Error::with_chain(e, ErrorKind::NetworkError(format!("Invalid content from \"{}\".", url))),
+			})?;
+
+		let content = adapter.parse_chapter(&doc, &fetched.url)?;
+		let served = &fetched.url;
+		let next = adapter.next_chapter_page(&doc, served, &chapter.link)
+						  .filter(|next| next != url && next != served);
+		let length = text_length(&content)?;
+		if length >= fetch.min_content_chars {
+			return Ok((content, next));
+		}
+		// A chapter that really is this short still comes in the usual markup.
+		if length > 0 && adapter.is_chapter_page(&doc) {
+			debug!("Chapter {} is only {} characters long on \"{}\"", chapter.index, length, url);
+			return Ok((content, next));
+		}
+		if let Some(challenge) = detect_challenge(&doc) {
+			return Err(challenge_error(served, challenge));
+		}
+
+		if attempt >= fetch.retries {
+			if length == 0 {
+				bail!(ErrorKind::NoContent(chapter.index, chapter.title.clone(), url.to_string()));
+			}
+			bail!(ErrorKind::ParseError(format!("Only found {} characters of content for \"Chapter {} - {}\" (\"{}\"), which looks like an error page.", length, chapter.index, chapter.title, url)));
+		}
+
+		let delay = retry_delay(attempt);
+		warn!("Chapter {} came back with only {} characters of content, retrying in {:?}", chapter.index, length, delay);
+		sleep(delay);
+		attempt += 1;
+	}
+}
+
+/// The SHA-256 of `content`, in lowercase hex.
+fn content_digest(content: &str) -> String {
+	format!("{:x}", Sha256::digest(content.as_bytes()))
+}
+
+/// Adds a bar for the download of `chapter` to `FetchOptions::downloads`, if there's one, counting off its bytes
+/// out of `length`, or spinning along with them if its length isn't known.
+fn download_bar<'a>(fetch: &'a FetchOptions, chapter: &Chapter, length: Option<u64>) -> Result<Option<(&'a MultiProgress, ProgressBar)>> {
+	let downloads = match fetch.downloads {
+		Some(ref downloads) => downloads,
+		None => return Ok(None),
+	};
+
+	let bar = match length {
+		Some(length) => ProgressBar::new(length).with_style(ProgressStyle::default_bar()
+			.template("  [{bar:30}] {bytes}/{total_bytes} ({bytes_per_sec}) {msg}")
+			.chain_err(|| "Invalid progress bar template.")?
+			.progress_chars("=>-")),
+		None => ProgressBar::new_spinner().with_style(ProgressStyle::default_spinner()
+			.template("  {spinner} {bytes} ({bytes_per_sec}) {msg}")
+			.chain_err(|| "Invalid progress bar template.")?),
+	};
+	let bar = downloads.add(bar);
+	bar.set_message(format!("Chapter {}", chapter.index));
+	Ok(Some((downloads, bar)))
+}
+
+/// How many words of text are in `content`, leaving out its markup and any stray punctuation.
+pub fn word_count(content: &str) -> Result<usize> {
+	let tag_regex = Regex::new(r"<[^>]*>")
+		.chain_err(|| "Unable to construct regex.")?;
+	Ok(tag_regex.replace_all(content, " ")
+				.split_whitespace()
+				.filter(|word| word.chars().any(char::is_alphanumeric))
+				.count())
+}
+
+/// Cuts `content` down to at most `limit` bytes, ending after the last whole paragraph that fits.
+///
+/// Should not even the first paragraph fit, it's cut part way through instead, closing whatever elements were
+/// left open, which can take it a little over `limit`.
+fn truncate_content(mut content: String, limit: usize) -> String {
+	let end = content.match_indices('\n')
+					 .map(|(i, _)| i + 1)
+					 .take_while(|&end| end <= limit)
+					 .last();
+	match end {
+		Some(end) => {
+			content.truncate(end);
+			content
+		}
+		None => cut_paragraph(&content, limit),
+	}
+}
+
+/// Cuts `content` at the last character boundary before `limit`, backing off out of any tag or entity it would
+/// cut in two, and closes the elements still open there.
+fn cut_paragraph(content: &str, limit: usize) -> String {
+	let mut end = (0..=limit.min(content.len())).rev()
+												.find(|&end| content.is_char_boundary(end))
+												.unwrap_or(0);
+	for marker in ['<', '&'] {
+		let closer = if marker == '<' { '>' } else { ';' };
+		if let Some(start) = content[..end].rfind(marker) {
+			if !content[start..end].contains(closer) {
+				end = start;
+			}
+		}
+	}
+
+	let mut cut = content[..end].to_owned();
+	let mut open: Vec<&str> = Vec::new();
+	for tag in content[..end].split('<').skip(1).filter_map(|tag| tag.split_once('>').map(|(tag, _)| tag)) {
+		if let Some(name) = tag.strip_prefix('/') {
+			if open.last() == Some(&name.trim()) {
+				open.pop();
+			}
+		} else if !tag.ends_with('/') && !tag.starts_with('!') {
+			open.push(tag.split_whitespace().next().unwrap_or_default());
+		}
+	}
+	for name in open.iter().rev() {
+		cut.push_str(&format!("</{}>", name));
+	}
+	cut.push('\n');
+	cut
+}
+
+/// How many characters of text are in `content`, leaving out its markup.
+fn text_length(content: &str) -> Result<usize> {
+	let tag_regex = Regex::new(r"<[^>]*>")
+		.chain_err(|| "Unable to construct regex.")?;
+	Ok(tag_regex.replace_all(content, "").trim().chars().count())
+}
+
+/// Fetches `chapters` on `jobs` threads, handing them to `consume` in order as they complete.
+///
+/// Threads never get more than `window_size(jobs)` chapters ahead of the one `consume` is waiting on, so only a
+/// handful of pages are held in memory at once, no matter how long the book is. With a single job no threads
+/// are spawned at all, and every chapter is fetched (and logged) in order, for reproducible runs.
+///
+/// Once `FetchOptions::interrupted` is set no more chapters are started, and the pages end after the ones already
+/// underway.
+pub fn fetch_chapters<R, F>(source: &dyn Source, chapters: &[Chapter], fetch: &FetchOptions, images: Option<&ImageStore>, bar: &ProgressBar, jobs: usize, consume: F) -> Result<R>
+	where F: FnOnce(OrderedPages) -> Result<R> {
+	let width = index_width(chapters);
+
+	// A single job is fetched on this thread as `consume` asks for each page, so everything happens in order.
+	if jobs == 1 {
+		let pages = chapters.iter()
+							.take_while(move |_| !fetch.interrupted.load(Ordering::SeqCst))
+							.map(move |chapter| fetch_page(source, chapter, fetch, images, bar, width));
+		return consume(OrderedPages {
+			source: PageSource::Serial(Box::new(pages)),
+		});
+	}
+
+	let window = Window::new(window_size(jobs));
+	let (sender, receiver) = channel();
+
+	scope(|scope| {
+		for _ in 0..jobs {
+			let sender = sender.clone();
+			let window = &window;
+			scope.spawn(move || {
+				while let Some(i) = window.claim(chapters.len(), fetch.interrupted) {
+					let page = fetch_page(source, &chapters[i], fetch, images, bar, width);
+					if sender.send((i, page)).is_err() {
+						break;
+					}
+				}
+			});
+		}
+		drop(sender);
+
+		let result = consume(OrderedPages {
+			source: PageSource::Threaded {
+				receiver,
+				pending: BTreeMap::new(),
+				next: 0,
+				window: &window,
+			},
+		});
+		// Stops the threads early if `consume` gave up before reaching the last chapter.
+		window.close();
+		result
+	})
+}
+
+/// Fetches a chapter for `fetch_chapters`, standing a placeholder in for it if it fails with `--skip-failed`
+/// and the failure is recoverable (see `Error::is_recoverable`).
+fn fetch_page(source: &dyn Source, chapter: &Chapter, fetch: &FetchOptions, images: Option<&ImageStore>, bar: &ProgressBar, width: usize) -> Result<Page> {
+	let result = fetch_chapter_content(source, chapter, fetch, images, bar, width);
+	let failures = match result {
+		Ok(_) => {
+			fetch.consecutive_failures.store(0, Ordering::SeqCst);
+			0
+		}
+		Err(_) => fetch.consecutive_failures.fetch_add(1, Ordering::SeqCst) + 1,
+	};
+	if let Some(limit) = fetch.abort_after {
+		if failures >= limit {
+			return result.chain_err(|| format!("The server appears to be unavailable, giving up after {} chapters in a row failed.", failures));
+		}
+	}
+
+	match result {
+		Err(e) if fetch.skip_failed && e.is_recoverable() => {
+			let reason = e.iter()
+						  .map(ToString::to_string)
+						  .collect::<Vec<_>>()
+						  .join(": ");
+			warn!("Skipping chapter {}: {}", chapter.index, reason);
+			fetch.failed.lock().unwrap().push((chapter.index, reason));
+			bar.inc(1);
+			Ok(placeholder_page(chapter, fetch, width))
+		}
+		page => page.chain_err(|| "Unable to fetch chapter content"),
+	}
+}
+
+/// Marks the content of a placeholder page, so `--update` fetches the chapter again instead of keeping it.
+pub const PLACEHOLDER_MARKER: &str = "<p class=\"fetch-failed\">";
+
+/// Stands in for a chapter that couldn't be fetched with `--skip-failed`.
+fn placeholder_page(chapter: &Chapter, fetch: &FetchOptions, width: usize) -> Page {
+	let link = escape_html(chapter.link.as_str());
+	let title = chapter.page_title(&fetch.title_format);
+	let content = format!("{}{} — failed to fetch from <a href=\"{}\">{}</a>.</p>\n", PLACEHOLDER_MARKER, escape_html(&title), link, link);
+	Page {
+		index: chapter.index,
+		name: chapter.padded_file_stem(width),
+		sha256: content_digest(&content),
+		content,
+		title,
+		volume: chapter.volume.clone(),
+		link: chapter.link.clone(),
+		words: 0,
+	}
+}
+
+/// How many chapters may be fetched or buffered ahead of the next one to be written.
+fn window_size(jobs: usize) -> usize {
+	jobs * 2
+}
+
+/// Hands out chapter positions to the fetch threads, holding them back while too far ahead of the writer.
+struct Window {
+	size: usize,
+	state: Mutex<WindowState>,
+	changed: Condvar,
+}
+
+struct WindowState {
+	claimed: usize,
+	emitted: usize,
+	closed: bool,
+}
+
+impl Window {
+	fn new(size: usize) -> Window {
+		Window {
+			size,
+			state: Mutex::new(WindowState {
+				claimed: 0,
+				emitted: 0,
+				closed: false,
+			}),
+			changed: Condvar::new(),
+		}
+	}
+
+	/// Blocks until the next position may be fetched, or returns `None` once there's nothing left to do.
+	fn claim(&self, total: usize, interrupted: &AtomicBool) -> Option<usize> {
+		let mut state = self.state.lock().unwrap();
+		loop {
+			if state.closed || state.claimed >= total || interrupted.load(Ordering::SeqCst) {
+				return None;
+			}
+			if state.claimed < state.emitted + self.size {
+				state.claimed += 1;
+				return Some(state.claimed - 1);
+			}
+			state = self.changed.wait(state).unwrap();
+		}
+	}
+
+	fn advance(&self) {
+		self.state.lock().unwrap().emitted += 1;
+		self.changed.notify_all();
+	}
+
+	fn close(&self) {
+		self.state.lock().unwrap().closed = true;
+		self.changed.notify_all();
+	}
+}
+
+/// The fetched pages, in the order their chapters were given to `fetch_chapters`.
+pub struct OrderedPages<'a> {
+	source: PageSource<'a>,
+}
+
+enum PageSource<'a> {
+	/// Pages fetched by the worker threads, put back in order as they come in.
+	Threaded {
+		receiver: Receiver<(usize, Result<Page>)>,
+		pending: BTreeMap<usize, Result<Page>>,
+		next: usize,
+		window: &'a Window,
+	},
+	/// Pages fetched one at a time, as they're asked for.
+	Serial(Box<dyn Iterator<Item = Result<Page>> + 'a>),
+}
+
+impl Iterator for OrderedPages<'_> {
+	type Item = Result<Page>;
+
+	fn next(&mut self) -> Option<Result<Page>> {
+		match self.source {
+			PageSource::Threaded { ref receiver, ref mut pending, ref mut next, window } => loop {
+				if let Some(page) = pending.remove(next) {
+					*next += 1;
+					window.advance();
+					return Some(page);
+				}
+				let (i, page) = receiver.recv().ok()?;
+				pending.insert(i, page);
+			},
+			PageSource::Serial(ref mut pages) => pages.next(),
+		}
+	}
+}
+
+/// A fetched chapter, holding the cleaned XHTML fragment of its content.
+#[derive(Debug)]
+pub struct Page {
+	pub index: u32,
+	/// The file stem of the chapter, see `Chapter::padded_file_stem`.
+	pub name: String,
+	pub title: String,
+	pub volume: Option<String>,
+	pub content: String,
+	/// Where the chapter was fetched from.
+	pub link: Url,
+	/// How many words the chapter has, see `word_count`.
+	pub words: usize,
+	/// The SHA-256 of `content`, in lowercase hex.
+	pub sha256: String,
+}
+
+impl Page {
+	/// Lists the page in a `--manifest`, to tell whether the chapter changed between downloads.
+	pub fn manifest_entry(&self) -> ManifestEntry {
+		ManifestEntry {
+			index: self.index,
+			title: self.title.clone(),
+			volume: self.volume.clone(),
+			url: self.link.clone(),
+			bytes: self.content.len(),
+			sha256: self.sha256.clone(),
+		}
+	}
+}
+
+/// A chapter as listed in a `--manifest`.
+#[derive(Debug, Clone, Serialize)]
+pub struct ManifestEntry {
+	pub index: u32,
+	pub title: String,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub volume: Option<String>,
+	#[serde(serialize_with = "serialize_url")]
+	pub url: Url,
+	/// The length of the cleaned content, in bytes.
+	pub bytes: usize,
+	pub sha256: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct BookInfo {
+	pub title: String,
+	/// The book page the info was scraped from.
+	#[serde(serialize_with = "serialize_url")]
+	pub url: Url,
+	pub author: Option<String>,
+	/// The synopsis, as plain text.
+	pub description: Option<String>,
+	/// The genres and tags the book is listed under.
+	pub tags: Vec<String>,
+	#[serde(serialize_with = "serialize_optional_url")]
+	pub cover_url: Option<Url>,
+	pub chapters: Vec<Chapter>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Chapter {
+	pub index: u32,
+	pub title: String,
+	#[serde(rename = "url", serialize_with = "serialize_url")]
+	pub link: Url,
+	/// Whether the index was parsed from the title, rather than synthesized.
+	pub numbered: bool,
+	/// The title of the volume or arc the chapter was listed under, if the book is split into several.
+	pub volume: Option<String>,
+	/// Which of the chapters sharing this index this is, starting at 1.
+	pub occurrence: u32,
+	/// When the chapter was published, as listed in the table of contents, if it shows one.
+	pub date: Option<String>,
+}
+
+impl Chapter {
+	/// The name used for this chapter's files, disambiguated when its index is shared with another chapter.
+	pub fn file_stem(&self) -> String {
+		if self.occurrence > 1 {
+			format!("chapter_{}_{}", self.index, self.occurrence)
+		} else {
+			format!("chapter_{}", self.index)
+		}
+	}
+
+	/// The heading of the chapter's page, with `{index}` and `{title}` in `format` filled in.
+	///
+	/// Chapters whose link gave nothing but the number are just numbered, rather than left with a dangling
+	/// separator.
+	pub fn page_title(&self, format: &str) -> String {
+		let title = self.title.trim();
+		if title.is_empty() {
+			return format!("Chapter {}", self.index);
+		}
+		format.replace("{index}", &self.index.to_string())
+			  .replace("{title}", title)
+	}
+
+	/// The file stem with its index zero-padded to `width` digits, so the files sort in reading order.
+	///
+	/// Only used for the files written out; the cache and `--update` go by the unpadded `file_stem`, which doesn't
+	/// change as the book grows.
+	pub fn padded_file_stem(&self, width: usize) -> String {
+		if self.occurrence > 1 {
+			format!("chapter_{:0width$}_{}", self.index, self.occurrence, width = width)
+		} else {
+			format!("chapter_{:0width$}", self.index, width = width)
+		}
+	}
+}
+
+/// How many digits the largest of the chapters' indices takes up.
+pub fn index_width(chapters: &[Chapter]) -> usize {
+	chapters.iter()
+			.map(|chapter| chapter.index.to_string().len())
+			.max()
+			.unwrap_or(1)
+}
+
+/// Fewer chapters than this are too few to tell boilerplate apart from coincidence.
+const MIN_BOILERPLATE_PAGES: usize = 5;
+
+/// Strips the paragraphs (translator notes, donation pleas and the like) that appear identically in at least
+/// `threshold` (a fraction, from 0 to 1) of the pages. Returns the paragraphs stripped.
+pub fn strip_boilerplate(pages: &mut [Page], threshold: f64) -> Vec<String> {
+	if pages.len() < MIN_BOILERPLATE_PAGES {
+		return Vec::new();
+	}
+
+	let mut counts: HashMap<&str, usize> = HashMap::new();
+	for page in pages.iter() {
+		let blocks: HashSet<&str> = page.content
+										.lines()
+										.filter(|line| line.starts_with("<p>"))
+										.collect();
+		for block in blocks {
+			*counts.entry(block).or_insert(0) += 1;
+		}
+	}
+
+	let minimum = (threshold * pages.len() as f64).ceil() as usize;
+	let boilerplate: HashSet<String> = counts.into_iter()
+											 .filter(|&(_, count)| count >= minimum.max(2))
+											 .map(|(block, _)| block.to_owned())
+											 .collect();
+	if boilerplate.is_empty() {
+		return Vec::new();
+	}
+
+	for page in pages.iter_mut() {
+		let mut content = String::with_capacity(page.content.len());
+		for line in page.content.lines().filter(|line| !boilerplate.contains(*line)) {
+			content.push_str(line);
+			content.push('\n');
+		}
+		page.content = content;
+	}
+
+	let mut stripped: Vec<String> = boilerplate.into_iter().collect();
+	stripped.sort();
+	for block in &stripped {
+		info!("Stripped boilerplate: {}", block);
+	}
+	stripped
+}
+
+/// Detects chapters sharing an index, which would otherwise overwrite each other's files.
+///
+/// When `strict`, any duplicate is an error listing the offending titles; otherwise the duplicates are
+/// numbered so their files don't collide. Returns the number of duplicated chapters.
+///
+/// Unnumbered chapters share the index of the one they follow (see `synthesize_indices`), which is numbered
+/// the same way, but isn't a duplicate.
+pub fn check_duplicates(chapters: &mut [Chapter], strict: bool) -> Result<usize> {
+	let mut seen: HashMap<u32, Vec<usize>> = HashMap::new();
+	for (i, chapter) in chapters.iter().enumerate() {
+		seen.entry(chapter.index).or_default().push(i);
+	}
+
+	let mut shared: Vec<Vec<usize>> = seen.into_values()
+										  .filter(|positions| positions.len() > 1)
+										  .collect();
+	shared.sort();
+	for positions in &shared {
+		for (occurrence, &i) in positions.iter().enumerate() {
+			chapters[i].occurrence = occurrence as u32 + 1;
+		}
+	}
+
+	let duplicates: Vec<Vec<usize>> = shared.into_iter()
+											.map(|positions| positions.into_iter().filter(|&i| chapters[i].numbered).collect::<Vec<_>>())
+											.filter(|positions| positions.len() > 1)
+											.collect();
+
+	let descriptions: Vec<String> = duplicates.iter()
+											  .map(|positions| {
+												  let titles = positions.iter()
+																		.map(|&i| format!("\"{}\"", chapters[i].title))
+																		.collect::<Vec<_>>()
+																		.join(", ");
+												  format!("Chapter {}: {}", chapters[positions[0]].index, titles)
+											  })
+											  .collect();
+
+	if strict && !duplicates.is_empty() {
+		bail!("Found chapters sharing an index: {}", descriptions.join("; "));
+	}
+
+	let mut count = 0;
+	for (positions, description) in duplicates.iter().zip(descriptions) {
+		warn!("Multiple chapters share an index, keeping them all: {}", description);
+		count += positions.len();
+	}
+	Ok(count)
+}
+
+/// Finds the indices missing from the numbering of `chapters`, as inclusive ranges in ascending order.
+///
+/// Only chapters whose index was parsed from their title count, and only between the first and last of them,
+/// so a book starting part way through or ending in unnumbered side stories has no gaps. Decimal chapters
+/// ("Chapter 12.5") take the index before the point, which is never missing as well.
+pub fn find_gaps(chapters: &[Chapter]) -> Vec<(u32, u32)> {
+	let indices: BTreeSet<u32> = chapters.iter()
+										 .filter(|chapter| chapter.numbered)
+										 .map(|chapter| chapter.index)
+										 .collect();
+	indices.iter()
+		   .zip(indices.iter().skip(1))
+		   .filter(|&(&previous, &next)| next > previous + 1)
+		   .map(|(&previous, &next)| (previous + 1, next - 1))
+		   .collect()
+}
+
+fn serialize_url<S: Serializer>(url: &Url, serializer: S) -> ::std::result::Result<S::Ok, S::Error> {
+	serializer.serialize_str(url.as_str())
+}
+
+fn serialize_optional_url<S: Serializer>(url: &Option<Url>, serializer: S) -> ::std::result::Result<S::Ok, S::Error> {
+	url.as_ref().map(Url::as_str).serialize(serializer)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use flate2::Compression;
+	use flate2::write::{GzEncoder, ZlibEncoder};
+	use crate::site::WuxiaWorldAdapter;
+	use std::io::{BufRead, BufReader, Write};
+	use std::net::TcpListener;
+	use std::sync::Arc;
+	use std::thread;
+
+	/// Serves a single response encoded with `encoding`, returning the URL it's served at.
+	fn serve_encoded(encoding: &'static str, body: Vec<u8>) -> Url {
+		let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+		let url = Url::parse(&format!("http://{}/chapter-1", listener.local_addr().unwrap())).unwrap();
+		thread::spawn(move || {
+			let (stream, _) = listener.accept().unwrap();
+			let mut reader = BufReader::new(stream);
+			let mut line = String::new();
+			while reader.read_line(&mut line).unwrap() > 2 {
+				line.clear();
+			}
+			let mut stream = reader.into_inner();
+			write!(stream, "HTTP/1.1 200 OK\r\nContent-Type: text/html\r\nContent-Encoding: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", encoding, body.len()).unwrap();
+			stream.write_all(&body).unwrap();
+		});
+		url
+	}
+
+	/// Fetches `url` with a client set up like the one `main` builds, and decodes what comes back.
+	fn fetch_decoded(url: Url) -> Result<String> {
+		let client = Client::builder().gzip(true).build().unwrap();
+		let res = client.get(url).send().unwrap();
+		let mut body = String::new();
+		decode_body(res)?.read_to_string(&mut body).unwrap();
+		Ok(body)
+	}
+
+	/// Answers every request to the URL returned with a 404, counting them in `requests`.
+	fn serve_not_found(requests: Arc<AtomicUsize>) -> Url {
+		let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+		let url = Url::parse(&format!("http://{}/novel/foo/chapter-1", listener.local_addr().unwrap())).unwrap();
+		thread::spawn(move || {
+			for stream in listener.incoming() {
+				let mut reader = BufReader::new(stream.unwrap());
+				let mut line = String::new();
+				while reader.read_line(&mut line).unwrap() > 2 {
+					line.clear();
+				}
+				requests.fetch_add(1, Ordering::SeqCst);
+				let mut stream = reader.into_inner();
+				write!(stream, "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n").unwrap();
+			}
+		});
+		url
+	}
+
+	#[test]
+	fn not_found_is_not_retried() {
+		let requests = Arc::new(AtomicUsize::new(0));
+		let url = serve_not_found(requests.clone());
+		let mut fetch = fetch_options();
+		fetch.retries = 3;
+		let error = execute_with_retry(&Client::new(), &CookieJar::new(), &url, &fetch).unwrap_err();
+		assert!(matches!(error.specific_kind(), ErrorKind::NotFound(_)), "{}", error);
+		assert_eq!(requests.load(Ordering::SeqCst), 1);
+	}
+
+	#[test]
+	fn chapters_that_are_not_found_have_no_content() {
+		let url = serve_not_found(Arc::new(AtomicUsize::new(0)));
+		let mut chapter = chapter(1);
+		chapter.link = url.clone();
+		let source = HttpSource::new(Client::new(), CookieJar::new());
+		let error = download_chapter_page(&source, &WuxiaWorldAdapter, &chapter, &url, &fetch_options()).unwrap_err();
+		assert!(matches!(error.specific_kind(), ErrorKind::NoContent(1, ..)), "{}", error);
+	}
+
+	/// The error of a request to `url` that couldn't be sent.
+	fn send_failure(url: &str) -> reqwest::Error {
+		Client::new().get(url).send().unwrap_err()
+	}
+
+	#[test]
+	fn refused_connections_are_connect_errors_whatever_the_url() {
+		// Nothing listens on the port once the listener is dropped.
+		let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+		let error = send_failure(&format!("http://127.0.0.1:{}/novel/castle-of-tls-handshakes/ssl-certificate", port));
+		assert!(is_connect_error(&error), "{}", error);
+		assert!(!is_tls_error(&error), "{}", error);
+	}
+
+	#[test]
+	fn failed_handshakes_are_tls_errors() {
+		let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+		let url = format!("https://127.0.0.1:{}/novel/foo", listener.local_addr().unwrap().port());
+		let server = thread::spawn(move || {
+			// Answers the handshake with plain HTTP.
+			let (mut stream, _) = listener.accept().unwrap();
+			let _ = stream.write_all(b"HTTP/1.1 400 Bad Request\r\nContent-Length: 0\r\n\r\n");
+		});
+		let error = send_failure(&url);
+		server.join().unwrap();
+		assert!(is_tls_error(&error), "{}", error);
+	}
+
+	#[test]
+	fn retry_delays_double_up_to_the_cap() {
+		assert_eq!(retry_delay(0), Duration::from_millis(RETRY_BASE_DELAY_MS));
+		assert_eq!(retry_delay(3), Duration::from_millis(RETRY_BASE_DELAY_MS * 8));
+		for attempt in [10, 63, 64, 1000, u32::MAX] {
+			assert_eq!(retry_delay(attempt), Duration::from_secs(MAX_RETRY_DELAY_SECS));
+		}
+	}
+
+	static NOT_INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+	/// Serves the chapters in `chapters` from memory, each after a delay of its own, and nothing else.
+	struct TestSource {
+		chapters: HashMap<Url, Duration>,
+	}
+
+	impl Source for TestSource {
+		fn get(&self, url: &Url, _fetch: &FetchOptions) -> Result<Fetched> {
+			let delay = self.chapters.get(url)
+							.chain_err(|| ErrorKind::NetworkError(format!("Nothing was found at \"{}\".", url)))?;
+			sleep(*delay);
+			let html = format!("<div class=\"fr-view\"><p>The text of {}.</p></div>", url.path());
+			Ok(Fetched {
+				url: url.clone(),
+				mime: Some("text/html".to_owned()),
+				charset: None,
+				length: None,
+				body: Box::new(io::Cursor::new(html.into_bytes())),
+			})
+		}
+	}
+
+	fn chapter(index: u32) -> Chapter {
+		Chapter {
+			index,
+			title: format!("Chapter {}", index),
+			link: Url::parse(&format!("https://www.wuxiaworld.com/novel/foo/chapter-{}", index)).unwrap(),
+			numbered: true,
+			volume: None,
+			occurrence: 1,
+			date: None,
+		}
+	}
+
+	fn fetch_options() -> FetchOptions {
+		FetchOptions {
+			retries: 0,
+			cache_dir: None,
+			rate_limiter: RateLimiter::new(Duration::from_millis(0)),
+			proxy: None,
+			existing: HashMap::new(),
+			plain: false,
+			punctuation: None,
+			skip_failed: false,
+			min_content_chars: 1,
+			interrupted: &NOT_INTERRUPTED,
+			abort_after: None,
+			consecutive_failures: AtomicUsize::new(0),
+			failed: Mutex::new(Vec::new()),
+			title_format: "Chapter {index}: {title}".to_owned(),
+			max_chapter_bytes: None,
+			words: AtomicUsize::new(0),
+			chapter_words: Mutex::new(Vec::new()),
+			downloads: None,
+		}
+	}
+
+	/// Fetches `chapters` with `jobs` threads, the ones in `missing` failing and each chapter taking longer the
+	/// earlier it's listed, so they finish in reverse.
+	fn fetch_all(chapters: &[Chapter], missing: &[u32], jobs: usize) -> Result<Vec<Page>> {
+		let source = TestSource {
+			chapters: chapters.iter()
+							  .filter(|chapter| !missing.contains(&chapter.index))
+							  .map(|chapter| (chapter.link.clone(), Duration::from_millis(5 * (chapters.len() - chapter.index as usize) as u64)))
+							  .collect(),
+		};
+		fetch_chapters(&source, chapters, &fetch_options(), None, &ProgressBar::hidden(), jobs, |pages| pages.collect())
+	}
+
+	#[test]
+	fn a_failing_chapter_fails_the_fetch() {
+		let chapters: Vec<Chapter> = (1..=6).map(chapter).collect();
+		for jobs in [1, 4] {
+			let error = fetch_all(&chapters, &[4], jobs).unwrap_err();
+			let chain: Vec<String> = error.iter().map(ToString::to_string).collect();
+			assert!(chain.iter().any(|message| message.contains("\"Chapter 4\"")), "{:?}", chain);
+			assert!(matches!(error.specific_kind(), ErrorKind::NetworkError(_)), "{:?}", chain);
+		}
+	}
+
+	/// Fails every request with the error it makes.
+	struct FailingSource(fn() -> Error);
+
+	impl Source for FailingSource {
+		fn get(&self, _url: &Url, _fetch: &FetchOptions) -> Result<Fetched> {
+			Err((self.0)())
+		}
+	}
+
+	#[test]
+	fn only_recoverable_failures_are_skipped() {
+		let mut fetch = fetch_options();
+		fetch.skip_failed = true;
+		let skipped = FailingSource(|| ErrorKind::NetworkError("Server error: 503".to_owned()).into());
+		let page = fetch_page(&skipped, &chapter(1), &fetch, None, &ProgressBar::hidden(), 1).unwrap();
+		assert!(page.content.starts_with(PLACEHOLDER_MARKER), "{}", page.content);
+		assert_eq!(fetch.failed.lock().unwrap().len(), 1);
+
+		let fatal = FailingSource(|| io::Error::new(io::ErrorKind::PermissionDenied, "Permission denied").into());
+		let error = fetch_page(&fatal, &chapter(2), &fetch, None, &ProgressBar::hidden(), 1).unwrap_err();
+		assert!(matches!(error.specific_kind(), ErrorKind::Io(_)), "{}", error);
+		assert_eq!(fetch.failed.lock().unwrap().len(), 1);
+	}
+
+	#[test]
+	fn chapters_missing_from_the_saved_pages_are_skipped() {
+		let mut fetch = fetch_options();
+		fetch.skip_failed = true;
+		let dir = std::env::temp_dir().join(format!("wuxia-dl-test-offline-{}", std::process::id()));
+		let source = OfflineSource::new(dir, Url::parse("https://www.wuxiaworld.com/novel/foo").unwrap());
+		let page = fetch_page(&source, &chapter(1), &fetch, None, &ProgressBar::hidden(), 1).unwrap();
+		assert!(page.content.starts_with(PLACEHOLDER_MARKER), "{}", page.content);
+		assert!(fetch.failed.lock().unwrap()[0].1.contains("No saved page"), "{:?}", fetch.failed.lock().unwrap());
+	}
+
+	#[test]
+	fn pages_come_out_in_chapter_order_however_they_finish() {
+		let chapters: Vec<Chapter> = (1..=8).map(chapter).collect();
+		for jobs in [1, 3, 8] {
+			let pages = fetch_all(&chapters, &[], jobs).unwrap();
+			assert_eq!(pages.iter().map(|page| page.index).collect::<Vec<_>>(), (1..=8).collect::<Vec<_>>());
+		}
+	}
+
+	#[test]
+	fn the_spine_follows_the_chapters_however_they_finish() {
+		let chapters: Vec<Chapter> = (1..=8).map(chapter).collect();
+		let info = BookInfo {
+			title: "Foo".to_owned(),
+			url: Url::parse("https://www.wuxiaworld.com/novel/foo").unwrap(),
+			author: None,
+			description: None,
+			tags: Vec::new(),
+			cover_url: None,
+			chapters: Vec::new(),
+		};
+		let options = EpubOptions {
+			title_page: false,
+			stylesheet: None,
+			lang: "en".to_owned(),
+			source_links: false,
+			title_format: "Chapter {index}: {title}".to_owned(),
+			identifier: None,
+			series: None,
+			toc_page: None,
+			pretty_xhtml: false,
+			meta: Vec::new(),
+			compression: None,
+		};
+		let source = TestSource {
+			chapters: chapters.iter()
+							  .map(|chapter| (chapter.link.clone(), Duration::from_millis(5 * (9 - chapter.index as u64))))
+							  .collect(),
+		};
+		let epub = fetch_chapters(&source, &chapters, &fetch_options(), None, &ProgressBar::hidden(), 8, |pages| generate_epub(&info, &options, None, None, pages)).unwrap();
+
+		let mut archive = zip::ZipArchive::new(io::Cursor::new(epub)).unwrap();
+		let mut package = String::new();
+		archive.by_name("OEBPS/content.opf").unwrap().read_to_string(&mut package).unwrap();
+		let spine: Vec<&str> = package.lines()
+									  .filter_map(|line| line.trim().strip_prefix("<itemref idref=\""))
+									  .filter_map(|line| line.split('"').next())
+									  .collect();
+		let expected: Vec<String> = (1..=8).map(|index| format!("chapter_{}_xhtml", index)).collect();
+		assert_eq!(spine, expected);
+	}
+
+	const FIXTURE: &str = "<html><body><div class=\"fr-view\"><p>Words here.</p></div></body></html>";
+
+	#[test]
+	fn gzip_responses_are_decoded() {
+		let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+		encoder.write_all(FIXTURE.as_bytes()).unwrap();
+		assert_eq!(fetch_decoded(serve_encoded("gzip", encoder.finish().unwrap())).unwrap(), FIXTURE);
+	}
+
+	#[test]
+	fn deflate_responses_are_decoded() {
+		let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+		encoder.write_all(FIXTURE.as_bytes()).unwrap();
+		assert_eq!(fetch_decoded(serve_encoded("deflate", encoder.finish().unwrap())).unwrap(), FIXTURE);
+	}
+
+	#[test]
+	fn unknown_encodings_are_an_error() {
+		assert!(fetch_decoded(serve_encoded("zstd", FIXTURE.as_bytes().to_vec())).is_err());
+	}
+
+	#[test]
+	fn sanitize_filename_replaces_slashes_and_colons() {
+		assert_eq!(sanitize_filename("Fate/Stay Night"), "Fate Stay Night");
+		assert_eq!(sanitize_filename("Re:Zero - Starting Life"), "Re Zero - Starting Life");
+		assert_eq!(sanitize_filename("What? \"Why\" <Not>|*\\"), "What Why Not");
+	}
+
+	#[test]
+	fn sanitize_filename_collapses_whitespace_and_trims_trailing_dots() {
+		assert_eq!(sanitize_filename("  A \t Tale\nOf  Two  "), "A Tale Of Two");
+		assert_eq!(sanitize_filename("The End..."), "The End");
+		assert_eq!(sanitize_filename("\u{7}Bell"), "Bell");
+	}
+
+	#[test]
+	fn escape_html_escapes_angle_brackets_and_ampersands() {
+		assert_eq!(escape_html("<System> Quest & Reward"), "&lt;System&gt; Quest &amp; Reward");
+		assert_eq!(escape_html("\"Don't\""), "&quot;Don&#39;t&quot;");
+		// Text is escaped as it is, entities and all, since it's only ever taken from already decoded text.
+		assert_eq!(escape_html("&amp;"), "&amp;amp;");
+	}
+
+	#[test]
+	fn truncating_keeps_the_whole_paragraphs_that_fit() {
+		let content = "<p>One.</p>\n<p>Two.</p>\n<p>Three.</p>\n".to_owned();
+		assert_eq!(truncate_content(content, 25), "<p>One.</p>\n<p>Two.</p>\n");
+	}
+
+	#[test]
+	fn truncating_a_single_long_paragraph_cuts_it_short() {
+		let content = "<p>Words <em>and words</em> &amp; more words, on and on.</p>\n".to_owned();
+		assert_eq!(truncate_content(content.clone(), 20), "<p>Words <em>and wor</em></p>\n");
+		// Never part way through a tag or an entity.
+		assert_eq!(truncate_content(content.clone(), 12), "<p>Words </p>\n");
+		assert_eq!(truncate_content(content, 32), "<p>Words <em>and words</em> </p>\n");
+	}
+
+	#[test]
+	fn sanitize_filename_falls_back_when_nothing_is_left() {
+		assert_eq!(sanitize_filename("///"), "book");
+		assert_eq!(sanitize_filename(". . ."), "book");
+	}
+}
@@ -1,238 +1,1934 @@
-#![recursion_limit = "1024"]
-
-extern crate epub_builder;
+extern crate ctrlc;
+extern crate base64;
+extern crate dirs;
+extern crate env_logger;
 #[macro_use]
 extern crate error_chain;
 extern crate indicatif;
-extern crate rayon;
+#[macro_use]
+extern crate log;
 extern crate regex;
 extern crate reqwest;
-extern crate select;
 extern crate url;
+extern crate uuid;
+extern crate wuxia_dl;
 
-use epub_builder::EpubBuilder;
-use epub_builder::EpubContent;
-use epub_builder::ReferenceType;
-use epub_builder::ZipLibrary;
-use indicatif::{ProgressBar, ProgressStyle};
-use rayon::prelude::*;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use indicatif::{MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle};
+use log::{Level, LevelFilter, Log, Metadata, Record};
 use regex::Regex;
-use reqwest::Client;
-use select::document::Document;
-use select::predicate::{Class, Name, Predicate};
-use self::errors::*;
-use std::env::args;
-use std::fs::{File, remove_file};
-use std::io::Cursor;
-use std::path::Path;
+use reqwest::{Client, Proxy};
+use reqwest::header::{ACCEPT_ENCODING, AUTHORIZATION, HeaderMap, HeaderValue, USER_AGENT};
+use std::env::{args, var};
+use std::collections::HashMap;
+use std::fs::{File, create_dir_all, metadata, read_to_string, remove_dir_all, remove_file, rename};
+use std::io::{self, BufWriter, IsTerminal, Write, stderr, stdin};
+use std::iter;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::Duration;
 use url::Url;
+use uuid::Uuid;
+use wuxia_dl::*;
+use wuxia_dl::errors::*;
+use wuxia_dl::source::Source;
+
+struct Options {
+	urls: Vec<String>,
+	output: Option<PathBuf>,
+	chapters: Option<ChapterRange>,
+	limit: Option<usize>,
+	jobs: usize,
+	retries: u32,
+	cache_dir: Option<PathBuf>,
+	no_cache: bool,
+	clear_cache: bool,
+	format: OutputFormat,
+	sort: bool,
+	strict_duplicates: bool,
+	/// Whether chapters missing from the numbering are an error, rather than a warning.
+	strict_sequence: bool,
+	user_agent: String,
+	read_timeout: Duration,
+	connect_timeout: Duration,
+	delay: Duration,
+	dry_run: bool,
+	verbosity: u8,
+	title_page: bool,
+	css: Option<PathBuf>,
+	lang: String,
+	with_content: bool,
+	update: Option<PathBuf>,
+	cover: Option<PathBuf>,
+	generate_cover: bool,
+	validate: bool,
+	strip_boilerplate: Option<f64>,
+	/// The fraction of the median chapter's words a chapter is flagged under, with `--flag-outliers`.
+	flag_outliers: Option<f64>,
+	proxy: Option<Url>,
+	/// Whether certificates go unchecked, leaving every connection open to being read or tampered with.
+	danger_accept_invalid_certs: bool,
+	config: Option<PathBuf>,
+	split: bool,
+	force: bool,
+	plain: bool,
+	no_progress: bool,
+	quiet: bool,
+	skip_failed: bool,
+	merge: bool,
+	title: Option<String>,
+	min_chars: usize,
+	exclude: Vec<Regex>,
+	source_links: bool,
+	abort_after: Option<usize>,
+	since: Option<u32>,
+	title_format: String,
+	uuid: Option<String>,
+	series: Option<String>,
+	series_index: Option<f64>,
+	max_chapter_bytes: Option<usize>,
+	/// The `Authorization` header sent with every request, kept out of the logs.
+	authorization: Option<String>,
+	/// A raw `Cookie` header, and a Netscape cookie file, for a session the user is signed into (see `CookieJar`).
+	cookie: Option<String>,
+	cookie_file: Option<PathBuf>,
+	/// The depth of the contents page, if there's to be one.
+	toc_page: Option<usize>,
+	pretty_xhtml: bool,
+	offline: Option<PathBuf>,
+	/// Where to write the chapters' hashes, see `write_manifest`.
+	manifest: Option<PathBuf>,
+	/// The `--meta` metadata, in the order given, see `parse_meta`.
+	meta: Vec<(String, String)>,
+	normalize_punct: Option<Punctuation>,
+	compression: Option<Compression>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum OutputFormat {
+	Epub,
+	Text,
+	Markdown,
+	Json,
+	Html,
+}
+
+impl OutputFormat {
+	/// Every format, in the order `--list-formats` lists them.
+	const ALL: &'static [OutputFormat] = &[OutputFormat::Epub, OutputFormat::Text, OutputFormat::Markdown, OutputFormat::Json, OutputFormat::Html];
+
+	/// Parses the name of a format, which is its extension.
+	fn parse(value: &str) -> Result<OutputFormat> {
+		match OutputFormat::ALL.iter().find(|format| format.extension() == value) {
+			Some(&format) => Ok(format),
+			None => {
+				let names: Vec<&str> = OutputFormat::ALL.iter()
+														.map(|format| format.extension())
+														.collect();
+				bail!("Unknown format \"{}\", expected one of: {}", value, names.join(", "))
+			}
+		}
+	}
+
+	fn description(self) -> &'static str {
+		match self {
+			OutputFormat::Epub => "An epub, with the cover and images embedded (the default)",
+			OutputFormat::Text => "Plain text",
+			OutputFormat::Markdown => "Markdown",
+			OutputFormat::Json => "The book's info as JSON, with each chapter's content given \"--with-content\"",
+			OutputFormat::Html => "A single HTML document, opening with a table of contents",
+		}
+	}
+
+	fn extension(self) -> &'static str {
+		match self {
+			OutputFormat::Epub => "epub",
+			OutputFormat::Text => "txt",
+			OutputFormat::Markdown => "md",
+			OutputFormat::Json => "json",
+			OutputFormat::Html => "html",
+		}
+	}
+
+	/// The extension of the files written with `--split`, where an epub's chapters become XHTML documents.
+	fn page_extension(self) -> &'static str {
+		match self {
+			OutputFormat::Epub => "xhtml",
+			format => format.extension(),
+		}
+	}
+
+	fn render_page(self, page: &Page, source_link: bool, pretty: bool) -> String {
+		match self {
+			OutputFormat::Epub | OutputFormat::Html if pretty => pretty_xhtml(&render_page_xhtml(page, source_link)),
+			OutputFormat::Epub | OutputFormat::Html => render_page_xhtml(page, source_link),
+			OutputFormat::Text => render_page_text(page),
+			OutputFormat::Markdown => render_page_markdown(page),
+			OutputFormat::Json => render_page_json(page),
+		}
+	}
+
+	/// Whether the chapters' images are downloaded and embedded, rather than left linking to the site.
+	fn embeds_images(self) -> bool {
+		self == OutputFormat::Epub
+	}
+
+	/// The phase left once every page is in, as a label and message for `Progress::phase`.
+	fn finishing_phase(self) -> (&'static str, &'static str) {
+		match self {
+			OutputFormat::Epub => ("EPUB", "Assembling epub"),
+			OutputFormat::Text | OutputFormat::Markdown | OutputFormat::Json | OutputFormat::Html => ("WRITE", "Writing file"),
+		}
+	}
+
+	/// Writes the whole book to `file` in this format, as the pages are fetched.
+	///
+	/// Only the epub is written all at once, after it's been assembled, so the others skip the `PhaseWriter`.
+	fn write_book<I>(self, info: &BookInfo, options: &EpubOptions, cover: Option<Image>, images: Option<&ImageStore>, pages: I, file: PhaseWriter<File>) -> Result<()>
+		where I: IntoIterator<Item = Result<Page>> {
+		match self {
+			OutputFormat::Epub => build_epub(info, options, cover, images, pages, file),
+			OutputFormat::Text => write_text(info, pages, BufWriter::new(file.into_inner())),
+			OutputFormat::Markdown => write_markdown(info, pages, BufWriter::new(file.into_inner())),
+			OutputFormat::Json => write_json(info, pages, BufWriter::new(file.into_inner())),
+			OutputFormat::Html => write_html(info, options, pages, BufWriter::new(file.into_inner())),
+		}
+	}
+}
+
+fn print_formats() {
+	for format in OutputFormat::ALL {
+		println!("{:<6}{}", format.extension(), format.description());
+	}
+}
+
+/// The environment variables flags that aren't on the command line fall back to, along with those flags.
+const ENV_FLAGS: &[(&str, &str)] = &[
+	("WUXIA_JOBS", "--jobs"),
+	("WUXIA_RETRIES", "--retries"),
+	("WUXIA_TIMEOUT", "--read-timeout"),
+	("WUXIA_CONNECT_TIMEOUT", "--connect-timeout"),
+	("WUXIA_DELAY_MS", "--delay-ms"),
+	("WUXIA_USER_AGENT", "--user-agent"),
+	("WUXIA_PROXY", "--proxy"),
+	("WUXIA_CACHE_DIR", "--cache-dir"),
+	("WUXIA_FORMAT", "--format"),
+	("WUXIA_LANG", "--lang"),
+	("WUXIA_CONFIG", "--config"),
+	("WUXIA_TITLE_FORMAT", "--title-format"),
+	("WUXIA_COOKIE_FILE", "--cookie-file"),
+];
+/// Like `ENV_FLAGS`, but for switches, which are turned on by `1`, `true` or `yes`.
+const ENV_SWITCHES: &[(&str, &str)] = &[
+	("WUXIA_NO_CACHE", "--no-cache"),
+	("WUXIA_FORCE", "--force"),
+	("WUXIA_PLAIN", "--plain"),
+	("WUXIA_SKIP_FAILED", "--skip-failed"),
+	("WUXIA_NO_PROGRESS", "--no-progress"),
+];
+
+/// How many chapters are fetched concurrently unless overridden with `--jobs`.
+const DEFAULT_JOBS: usize = 4;
+/// How many times a failed chapter request is retried unless overridden with `--retries`.
+const DEFAULT_RETRIES: u32 = 3;
+/// The most retries `--retries` accepts, which even at the longest delay is well over an hour of waiting per chapter.
+const MAX_RETRIES: u32 = 100;
+/// Upper bound on waiting for the server, whether for the response or for each read of its body, unless overridden with `--read-timeout`.
+const DEFAULT_READ_TIMEOUT_SECS: u64 = 30;
+/// Upper bound on establishing a connection unless overridden with `--connect-timeout`.
+const DEFAULT_CONNECT_TIMEOUT_SECS: u64 = 10;
+/// Chapters shorter than this many characters are suspected to be error pages unless overridden with `--min-chars`.
+const DEFAULT_MIN_CHARS: usize = 100;
+/// What chapters are headed with unless overridden with `--title-format`.
+const DEFAULT_TITLE_FORMAT: &str = "Chapter {index}: {title}";
+/// Chapters with less than this fraction of the median chapter's words are flagged by `--flag-outliers`, unless
+/// overridden with `--outlier-fraction`.
+const DEFAULT_OUTLIER_FRACTION: f64 = 0.25;
+
+/// How deep the contents page goes unless overridden with `--toc-depth`.
+const DEFAULT_TOC_DEPTH: usize = 2;
+/// How often the spinner next to the current phase moves.
+const SPINNER_TICK_MS: u64 = 100;
+/// The language of the content unless overridden with `--lang`.
+const DEFAULT_LANG: &str = "en";
+/// Sent with every request unless overridden with `--user-agent`, as some CDNs block unknown clients.
+const DEFAULT_USER_AGENT: &str = "Mozilla/5.0 (Windows NT 10.0; Win64; x64; rv:115.0) Gecko/20100101 Firefox/115.0";
+
+/// An inclusive range of chapter indices, either end of which may be left open.
+#[derive(Debug, Clone, Copy)]
+struct ChapterRange {
+	start: Option<u32>,
+	end: Option<u32>,
+}
+
+impl ChapterRange {
+	/// Parses `N`, `START-END`, `START-` or `-END`.
+	fn parse(value: &str) -> Result<ChapterRange> {
+		let parse_index = |raw: &str| -> Result<Option<u32>> {
+			let raw = raw.trim();
+			if raw.is_empty() {
+				return Ok(None);
+			}
+			raw.parse::<u32>()
+			   .map(Some)
+			   .chain_err(|| format!("Unable to parse chapter index \"{}\"", raw))
+		};
 
-type Pages = Vec<EpubContent<Cursor<String>>>;
+		let range = match value.find('-') {
+			Some(i) => ChapterRange {
+				start: parse_index(&value[..i])?,
+				end: parse_index(&value[i + 1..])?,
+			},
+			None => {
+				let index = parse_index(value)?
+					.chain_err(|| "Empty chapter range")?;
+				ChapterRange {
+					start: Some(index),
+					end: Some(index),
+				}
+			}
+		};
+
+		if let (Some(start), Some(end)) = (range.start, range.end) {
+			if start > end {
+				bail!("Invalid chapter range \"{}\": start is after end", value);
+			}
+		}
 
-mod errors {
-	error_chain! {}
+		Ok(range)
+	}
+
+	fn contains(&self, index: u32) -> bool {
+		self.start.is_none_or(|start| index >= start) && self.end.is_none_or(|end| index <= end)
+	}
 }
 
 fn main() {
 	let args: Vec<String> = args().collect();
 	let program = &args[0];
 
-	if args.len() != 2 {
-		println!("Usage: {} <url>", program);
+	if args[1..].iter().any(|arg| arg == "--help" || arg == "-h") {
+		print_help(program);
+		return;
+	}
+	if args[1..].iter().any(|arg| arg == "--list-formats") {
+		print_formats();
 		return;
 	}
 
-	if let Err(e) = run(args) {
-		use std::io::Write;
-		use error_chain::ChainedError;
-		let stderr = &mut ::std::io::stderr();
+	// Given first, so that the command line's own flags take precedence.
+	let args: Vec<String> = env_args().into_iter().chain(args[1..].iter().cloned()).collect();
+	let options = match parse_args(&args) {
+		Ok(options) => options,
+		Err(e) => {
+			eprintln!("{}", e);
+			eprintln!("{}", usage(program));
+			::std::process::exit(1);
+		}
+	};
+
+	init_logger(options.verbosity, options.quiet);
+
+	if let Err(e) = load_config(options.config.as_deref()).and_then(|config| configure_selectors(&config.selectors)) {
+		report_error(&e);
+		::std::process::exit(1);
+	}
+
+	install_interrupt_handler();
+
+	if options.merge {
+		if let Err(e) = run_merged(&options) {
+			report_error(&e);
+			::std::process::exit(1);
+		}
+		return;
+	}
+
+	// Every book is attempted, so one failing doesn't hold back the rest.
+	let total = options.urls.len();
+	let mut succeeded = Vec::new();
+	let mut failed = Vec::new();
+	for (i, url) in options.urls.iter().enumerate() {
+		if interrupted() {
+			break;
+		}
+		let position = if total > 1 {
+			format!("{}/{} ", i + 1, total)
+		} else {
+			String::new()
+		};
+
+		match run(&options, url, &position) {
+			Ok(()) => succeeded.push(url),
+			Err(e) => {
+				report_error(&e);
+				failed.push(url);
+			}
+		}
+	}
+
+	if total > 1 {
+		info!("Downloaded {} of {} books.", succeeded.len(), total);
+		for url in &succeeded {
+			info!("  Succeeded: {}", url);
+		}
+		for url in &failed {
+			info!("  Failed: {}", url);
+		}
+	}
 
-		writeln!(stderr, "{}", e.display_chain()).expect("Error writing to stderr");
+	if !failed.is_empty() {
 		::std::process::exit(1);
 	}
 }
 
-fn run(args: Vec<String>) -> Result<()> {
-	let url = &args[1];
+/// Set by the first Ctrl-C, so the book being fetched is saved with what's been fetched so far.
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+fn interrupted() -> bool {
+	INTERRUPTED.load(Ordering::SeqCst)
+}
+
+/// Stops fetching on the first Ctrl-C, letting the chapters underway finish, and exits on the second.
+fn install_interrupt_handler() {
+	let result = ctrlc::set_handler(|| {
+		if INTERRUPTED.swap(true, Ordering::SeqCst) {
+			::std::process::exit(130);
+		}
+		warn!("Interrupted, finishing the chapters underway. Press Ctrl-C again to quit immediately.");
+	});
+	if let Err(e) = result {
+		warn!("Unable to handle Ctrl-C: {}", e);
+	}
+}
+
+/// Where the output of an interrupted download is saved, so it isn't mistaken for the whole book.
+fn partial_path(path: &Path) -> PathBuf {
+	let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+	match path.extension() {
+		Some(extension) => path.with_file_name(format!("{} (partial).{}", stem, extension.to_string_lossy())),
+		None => path.with_file_name(format!("{} (partial)", stem)),
+	}
+}
+
+/// Logs `error` along with everything that caused it.
+fn report_error(error: &Error) {
+	let causes: Vec<String> = error.iter()
+								   .map(ToString::to_string)
+								   .collect();
+	error!("{}", causes.join("\n  Caused by: "));
+}
+
+/// The progress bars currently being drawn, if any, which log records have to be printed around.
+static ACTIVE_PROGRESS: Mutex<Option<MultiProgress>> = Mutex::new(None);
+
+/// Filters records the same way `env_logger` does, but hides the active progress bars while writing one,
+/// so records never end up drawn over them.
+struct BarLogger {
+	filter: env_logger::Logger,
+}
+
+impl Log for BarLogger {
+	fn enabled(&self, metadata: &Metadata) -> bool {
+		self.filter.enabled(metadata)
+	}
+
+	fn log(&self, record: &Record) {
+		if !self.filter.matches(record) {
+			return;
+		}
+
+		let line = match record.level() {
+			Level::Info => record.args().to_string(),
+			Level::Warn => format!("Warning: {}", record.args()),
+			Level::Error => format!("Error: {}", record.args()),
+			level => format!("[{}] {}: {}", level, record.target(), record.args()),
+		};
+		match *ACTIVE_PROGRESS.lock().unwrap() {
+			Some(ref progress) => progress.suspend(|| eprintln!("{}", line)),
+			None => eprintln!("{}", line),
+		}
+	}
+
+	fn flush(&self) {}
+}
+
+/// Logs this crate at info, or debug and trace with `-v` and `-vv`, and everything else at warn.
+/// `RUST_LOG` takes precedence over both, but not over `--quiet`, which only lets errors through.
+fn init_logger(verbosity: u8, quiet: bool) {
+	let mut builder = env_logger::Builder::new();
+	if quiet {
+		builder.filter_level(LevelFilter::Error);
+	} else {
+		let level = match verbosity {
+			0 => LevelFilter::Info,
+			1 => LevelFilter::Debug,
+			_ => LevelFilter::Trace,
+		};
+		builder.filter_level(LevelFilter::Warn)
+			   .filter_module("wuxia_dl", level)
+			   .parse_default_env();
+	}
+	let filter = builder.build();
+
+	log::set_max_level(filter.filter());
+	log::set_boxed_logger(Box::new(BarLogger { filter })).expect("Logger was already initialised");
+}
+
+/// Routes log records around `progress` until it is finished (or `None` is given).
+fn set_active_progress(progress: Option<&MultiProgress>) {
+	*ACTIVE_PROGRESS.lock().unwrap() = progress.cloned();
+}
+
+/// What's drawn while a book is downloaded: a line naming the phase it's in (fetching the table of contents,
+/// downloading chapters, assembling the epub, writing the file), with the chapters' progress bar beneath it.
+///
+/// The chapters' bar is left behind once it's done, while the phase line is cleared when this is dropped.
+struct Progress {
+	multi: MultiProgress,
+	phase: ProgressBar,
+	/// The book's position among the books, prefixing each label.
+	position: String,
+}
+
+impl Progress {
+	fn new(options: &Options, position: &str) -> Result<Progress> {
+		// The bars would only end up as control characters in a log file.
+		let multi = if options.quiet || options.no_progress || !stderr().is_terminal() {
+			MultiProgress::with_draw_target(ProgressDrawTarget::hidden())
+		} else {
+			MultiProgress::new()
+		};
+		let phase = multi.add(ProgressBar::new_spinner());
+		phase.set_style(ProgressStyle::default_spinner()
+			.template("[{prefix}] {spinner} {msg}")
+			.chain_err(|| "Invalid progress bar template.")?);
+		set_active_progress(Some(&multi));
+
+		Ok(Progress {
+			multi,
+			phase,
+			position: position.to_owned(),
+		})
+	}
+
+	/// Moves on to the phase labelled `label`, described by `message`.
+	fn phase(&self, label: &str, message: impl Into<String>) {
+		self.phase.set_prefix(format!("{}{}", self.position, label));
+		self.phase.set_message(message.into());
+		// Only started now, so it's never drawn without a label.
+		self.phase.enable_steady_tick(Duration::from_millis(SPINNER_TICK_MS));
+	}
+
+	/// Moves on to downloading `size` chapters, returning the bar that counts them off, which stays hidden if
+	/// nothing is `fetched`.
+	fn chapters(&self, size: usize, fetched: bool) -> Result<ProgressBar> {
+		if !fetched {
+			self.phase("JSON", "Writing the book info");
+			return Ok(ProgressBar::hidden());
+		}
+
+		self.phase("FETCH", format!("Downloading {} chapters", size));
+		let bar = self.multi.add(ProgressBar::new(size as u64));
+		bar.set_style(ProgressStyle::default_bar()
+			.template("[{prefix}] [{bar:40}] {pos}/{len} ({eta}) {msg}")
+			.chain_err(|| "Invalid progress bar template.")?
+			.progress_chars("=>-"));
+		bar.set_prefix(format!("{}FETCH", self.position));
+		bar.set_message("");
+		Ok(bar)
+	}
+
+	/// Runs `f` with everything hidden, to prompt the user without being drawn over.
+	fn suspend<F: FnOnce() -> R, R>(&self, f: F) -> R {
+		self.multi.suspend(f)
+	}
+}
+
+impl Drop for Progress {
+	fn drop(&mut self) {
+		self.phase.finish_and_clear();
+		set_active_progress(None);
+	}
+}
+
+/// Moves `progress` on to writing the file once anything is written to `inner`, as the epub is only written
+/// out after it's been assembled in memory.
+struct PhaseWriter<'a, W> {
+	inner: W,
+	progress: &'a Progress,
+	writing: bool,
+}
+
+impl<'a, W> PhaseWriter<'a, W> {
+	fn new(inner: W, progress: &'a Progress) -> PhaseWriter<'a, W> {
+		PhaseWriter {
+			inner,
+			progress,
+			writing: false,
+		}
+	}
+
+	fn into_inner(self) -> W {
+		self.inner
+	}
+}
+
+impl<'a, W: Write> Write for PhaseWriter<'a, W> {
+	fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+		if !self.writing {
+			self.writing = true;
+			self.progress.phase("WRITE", "Writing file");
+		}
+		self.inner.write(buf)
+	}
+
+	fn flush(&mut self) -> io::Result<()> {
+		self.inner.flush()
+	}
+}
+
+fn usage(program: &str) -> String {
+	format!("Usage: {} [--help] [--list-formats] [--output <path>] [--chapters <start-end>] [--exclude <regex>]... [--since <index>] [--limit <n>] [--jobs <n>] [--retries|--max-retries <n>] [--abort-after <n>] [--cache-dir <dir>] [--no-cache] [--clear-cache] [--format <epub|txt|md|json|html>] [--with-content] [--update <epub>] [--no-sort] [--duplicates <strict|lenient>] [--strict-sequence] [--user-agent <string>] [--read-timeout <secs>] [--connect-timeout <secs>] [--delay-ms <n>] [--dry-run] [-v|-vv|--quiet] [--no-progress] [--no-title-page] [--toc-page [--toc-depth <1|2>]] [--pretty-xhtml] [--compression <stored|fast|best>] [--css <file>] [--lang <code>] [--url-file <file>] [--cover <file>] [--generate-cover] [--validate] [--strip-boilerplate <percent>] [--flag-outliers [--outlier-fraction <fraction>]] [--proxy <url>] [--danger-accept-invalid-certs] [--auth-bearer <token>|--auth-basic <user:pass>] [--cookie <header>] [--cookie-file <file>] [--config <file>] [--split] [--force] [--plain] [--normalize-punct <ascii|typographic>] [--source-links] [--skip-failed] [--min-chars <n>] [--max-chapter-bytes <n>] [--title-format <template>] [--uuid <uuid>] [--series <name> [--series-index <n>]] [--meta <key=value>]... [--title <title>] [--merge] [--offline <dir>] [--manifest <file>] <url>... [output]", program)
+}
+
+fn print_help(program: &str) {
+	println!("{}", usage(program));
+	println!();
+	println!("Flags left off the command line fall back to these environment variables, and only then to their defaults:");
+	for (name, flag) in ENV_FLAGS.iter().chain(ENV_SWITCHES) {
+		println!("  {:<24}{}", name, flag);
+	}
+	println!("  {:<24}--output", "WUXIA_OUTPUT");
+	println!("The switches are turned on by \"1\", \"true\" or \"yes\".");
+}
+
+/// The flags set through the environment, see `ENV_FLAGS` and `ENV_SWITCHES`.
+fn env_args() -> Vec<String> {
+	let mut args = Vec::new();
+	for (name, flag) in ENV_FLAGS {
+		if let Ok(value) = var(name) {
+			if !value.is_empty() {
+				args.push((*flag).to_owned());
+				args.push(value);
+			}
+		}
+	}
+	for (name, flag) in ENV_SWITCHES {
+		if var(name).is_ok_and(|value| matches!(value.trim().to_ascii_lowercase().as_str(), "1" | "true" | "yes")) {
+			args.push((*flag).to_owned());
+		}
+	}
+	args
+}
+
+fn parse_args(args: &[String]) -> Result<Options> {
+	let mut urls = Vec::new();
+	let mut output = None;
+	let mut chapters = None;
+	let mut limit = None;
+	let mut jobs = DEFAULT_JOBS;
+	let mut retries = DEFAULT_RETRIES;
+	let mut cache_dir = None;
+	let mut no_cache = false;
+	let mut offline = None;
+	let mut manifest = None;
+	let mut meta = Vec::new();
+	let mut normalize_punct = None;
+	let mut compression = None;
+	let mut clear_cache = false;
+	let mut format = OutputFormat::Epub;
+	let mut sort = true;
+	let mut strict_duplicates = false;
+	let mut strict_sequence = false;
+	let mut user_agent = DEFAULT_USER_AGENT.to_owned();
+	let mut read_timeout = Duration::from_secs(DEFAULT_READ_TIMEOUT_SECS);
+	let mut connect_timeout = Duration::from_secs(DEFAULT_CONNECT_TIMEOUT_SECS);
+	let mut delay = Duration::from_millis(0);
+	let mut dry_run = false;
+	let mut verbosity = 0;
+	let mut title_page = true;
+	let mut css = None;
+	let mut lang = DEFAULT_LANG.to_owned();
+	let mut with_content = false;
+	let mut update = None;
+	let mut cover = None;
+	let mut generate_cover = false;
+	let mut validate = false;
+	let mut strip_boilerplate = None;
+	let mut flag_outliers = false;
+	let mut outlier_fraction = None;
+	let mut proxy = None;
+	let mut danger_accept_invalid_certs = false;
+	let mut config = None;
+	let mut split = false;
+	let mut force = false;
+	let mut plain = false;
+	let mut no_progress = false;
+	let mut quiet = false;
+	let mut skip_failed = false;
+	let mut merge = false;
+	let mut title = None;
+	let mut min_chars = DEFAULT_MIN_CHARS;
+	let mut exclude = Vec::new();
+	let mut source_links = false;
+	let mut abort_after = None;
+	let mut since = None;
+	let mut title_format = DEFAULT_TITLE_FORMAT.to_owned();
+	let mut uuid = None;
+	let mut series = None;
+	let mut series_index = None;
+	let mut max_chapter_bytes = None;
+	let mut authorization = None;
+	let mut cookie = None;
+	let mut cookie_file = None;
+	let mut toc_page = false;
+	let mut toc_depth = None;
+	let mut pretty_xhtml = false;
+
+	let mut args = args.iter();
+	while let Some(arg) = args.next() {
+		match arg.as_str() {
+			"--output" | "-o" => output = Some(PathBuf::from(next_value(&mut args, arg)?)),
+			"--chapters" => chapters = Some(ChapterRange::parse(next_value(&mut args, arg)?)?),
+			"--since" => {
+				let value = next_value(&mut args, arg)?;
+				since = Some(value.parse::<u32>()
+								  .chain_err(|| format!("Unable to parse chapter index \"{}\"", value))?);
+			}
+			"--exclude" => {
+				let value = next_value(&mut args, arg)?;
+				exclude.push(Regex::new(value)
+					.chain_err(|| format!("Invalid exclude pattern \"{}\"", value))?);
+			}
+			"--limit" => {
+				let value = next_value(&mut args, arg)?;
+				let count = value.parse::<usize>()
+								 .chain_err(|| format!("Unable to parse chapter limit \"{}\"", value))?;
+				if count < 1 {
+					bail!("Chapter limit must be at least 1.");
+				}
+				limit = Some(count);
+			}
+			"--jobs" | "-j" => {
+				let value = next_value(&mut args, arg)?;
+				jobs = value.parse::<usize>()
+							.chain_err(|| format!("Unable to parse job count \"{}\"", value))?;
+				if jobs < 1 {
+					bail!("Job count must be at least 1.");
+				}
+			}
+			"--retries" | "--max-retries" => {
+				let value = next_value(&mut args, arg)?;
+				retries = value.parse::<u32>()
+							   .chain_err(|| format!("Unable to parse retry count \"{}\"", value))?;
+				if retries > MAX_RETRIES {
+					bail!("The retry count can be at most {}.", MAX_RETRIES);
+				}
+			}
+			"--abort-after" => {
+				let value = next_value(&mut args, arg)?;
+				let count = value.parse::<usize>()
+								 .chain_err(|| format!("Unable to parse failure count \"{}\"", value))?;
+				if count < 1 {
+					bail!("The failure count must be at least 1.");
+				}
+				abort_after = Some(count);
+			}
+			"--cache-dir" => cache_dir = Some(PathBuf::from(next_value(&mut args, arg)?)),
+			"--no-cache" => no_cache = true,
+			"--offline" => offline = Some(PathBuf::from(next_value(&mut args, arg)?)),
+			"--manifest" => manifest = Some(PathBuf::from(next_value(&mut args, arg)?)),
+			"--meta" => meta.push(parse_meta(next_value(&mut args, arg)?)?),
+			"--compression" => {
+				compression = match next_value(&mut args, arg)?.as_str() {
+					"stored" => Some(Compression::Stored),
+					"fast" => Some(Compression::Fast),
+					"best" => Some(Compression::Best),
+					value => bail!("Unknown compression \"{}\", expected \"stored\", \"fast\" or \"best\"", value),
+				};
+			}
+			"--normalize-punct" => {
+				normalize_punct = match next_value(&mut args, arg)?.as_str() {
+					"ascii" => Some(Punctuation::Ascii),
+					"typographic" => Some(Punctuation::Typographic),
+					value => bail!("Unknown punctuation \"{}\", expected \"ascii\" or \"typographic\"", value),
+				};
+			}
+			"--clear-cache" => clear_cache = true,
+			"--format" | "-f" => format = OutputFormat::parse(next_value(&mut args, arg)?)?,
+			"--no-sort" => sort = false,
+			"--duplicates" => {
+				strict_duplicates = match next_value(&mut args, arg)?.as_str() {
+					"strict" => true,
+					"lenient" => false,
+					value => bail!("Unknown duplicate handling \"{}\", expected \"strict\" or \"lenient\"", value),
+				};
+			}
+			"--strict-sequence" => strict_sequence = true,
+			"--user-agent" => user_agent = next_value(&mut args, arg)?.clone(),
+			"--read-timeout" | "--timeout" => read_timeout = parse_seconds(next_value(&mut args, arg)?)?,
+			"--connect-timeout" => connect_timeout = parse_seconds(next_value(&mut args, arg)?)?,
+			"--delay-ms" => {
+				let value = next_value(&mut args, arg)?;
+				let millis = value.parse::<u64>()
+								  .chain_err(|| format!("Unable to parse delay \"{}\"", value))?;
+				delay = Duration::from_millis(millis);
+			}
+			"--dry-run" => dry_run = true,
+			"--verbose" | "-v" => verbosity += 1,
+			"-vv" => verbosity += 2,
+			"--no-title-page" => title_page = false,
+			"--css" => css = Some(PathBuf::from(next_value(&mut args, arg)?)),
+			"--lang" => lang = parse_lang(next_value(&mut args, arg)?)?,
+			"--with-content" => with_content = true,
+			"--update" => update = Some(PathBuf::from(next_value(&mut args, arg)?)),
+			"--cover" => cover = Some(PathBuf::from(next_value(&mut args, arg)?)),
+			"--generate-cover" => generate_cover = true,
+			"--validate" => validate = true,
+			"--strip-boilerplate" => {
+				let value = next_value(&mut args, arg)?;
+				let percent = value.parse::<f64>()
+								   .chain_err(|| format!("Unable to parse percentage \"{}\"", value))?;
+				if !(percent > 0.0 && percent <= 100.0) {
+					bail!("The boilerplate threshold must be a percentage between 0 and 100.");
+				}
+				strip_boilerplate = Some(percent / 100.0);
+			}
+			"--flag-outliers" => flag_outliers = true,
+			"--outlier-fraction" => {
+				let value = next_value(&mut args, arg)?;
+				let fraction = value.parse::<f64>()
+									.chain_err(|| format!("Unable to parse fraction \"{}\"", value))?;
+				if !(fraction > 0.0 && fraction < 1.0) {
+					bail!("The outlier fraction must be between 0 and 1.");
+				}
+				outlier_fraction = Some(fraction);
+			}
+			"--proxy" => proxy = Some(parse_proxy(next_value(&mut args, arg)?)?),
+			"--danger-accept-invalid-certs" => danger_accept_invalid_certs = true,
+			"--config" => config = Some(PathBuf::from(next_value(&mut args, arg)?)),
+			"--split" => split = true,
+			"--force" | "--yes" | "-y" => force = true,
+			"--plain" => plain = true,
+			"--no-progress" => no_progress = true,
+			"--source-links" => source_links = true,
+			"--quiet" | "-q" => quiet = true,
+			"--skip-failed" => skip_failed = true,
+			"--merge" => merge = true,
+			"--title" => title = Some(next_value(&mut args, arg)?.clone()),
+			"--title-format" => {
+				title_format = next_value(&mut args, arg)?.clone();
+				if !title_format.contains("{index}") && !title_format.contains("{title}") {
+					bail!("The title format \"{}\" has neither \"{{index}}\" nor \"{{title}}\" in it.", title_format);
+				}
+			}
+			"--uuid" => {
+				let value = next_value(&mut args, arg)?;
+				let parsed = Uuid::parse_str(value)
+								  .chain_err(|| format!("Unable to parse UUID \"{}\"", value))?;
+				uuid = Some(parsed.urn().to_string());
+			}
+			"--series" => series = Some(next_value(&mut args, arg)?.clone()),
+			"--series-index" => {
+				let value = next_value(&mut args, arg)?;
+				let index = value.parse::<f64>()
+								 .chain_err(|| format!("Unable to parse series index \"{}\"", value))?;
+				if !index.is_finite() || index < 0.0 {
+					bail!("The series index must be a number no less than 0.");
+				}
+				series_index = Some(index);
+			}
+			"--max-chapter-bytes" => {
+				let value = next_value(&mut args, arg)?;
+				let bytes = value.parse::<usize>()
+								 .chain_err(|| format!("Unable to parse byte count \"{}\"", value))?;
+				if bytes < 1 {
+					bail!("The chapter size limit must be at least 1 byte.");
+				}
+				max_chapter_bytes = Some(bytes);
+			}
+			"--auth-bearer" | "--auth-basic" => {
+				if authorization.is_some() {
+					bail!("Only one of \"--auth-bearer\" and \"--auth-basic\" can be given.");
+				}
+				let value = next_value(&mut args, arg)?;
+				authorization = Some(if arg == "--auth-bearer" {
+					format!("Bearer {}", value)
+				} else {
+					if !value.contains(':') {
+						bail!("\"--auth-basic\" expects \"<user>:<password>\".");
+					}
+					format!("Basic {}", BASE64.encode(value))
+				});
+			}
+			"--cookie" => cookie = Some(next_value(&mut args, arg)?.clone()),
+			"--cookie-file" => cookie_file = Some(PathBuf::from(next_value(&mut args, arg)?)),
+			"--toc-page" => toc_page = true,
+			"--pretty-xhtml" => pretty_xhtml = true,
+			"--toc-depth" => {
+				let value = next_value(&mut args, arg)?;
+				toc_depth = match value.as_str() {
+					"1" => Some(1),
+					"2" => Some(2),
+					_ => bail!("Invalid contents depth \"{}\", expected 1 (chapters only) or 2 (grouped by volume).", value),
+				};
+			}
+			"--min-chars" => {
+				let value = next_value(&mut args, arg)?;
+				min_chars = value.parse::<usize>()
+								 .chain_err(|| format!("Unable to parse character count \"{}\"", value))?;
+			}
+			"--url-file" => {
+				let path = next_value(&mut args, arg)?;
+				let contents = read_to_string(path)
+					.chain_err(|| format!("Unable to read url file: \"{}\"", path))?;
+				urls.extend(contents.lines()
+									.map(str::trim)
+									.filter(|line| !line.is_empty() && !line.starts_with('#'))
+									.map(str::to_owned));
+			}
+			_ if arg.starts_with('-') => bail!("Unknown option: \"{}\"", arg),
+			_ if urls.is_empty() || arg.contains("://") => urls.push(arg.clone()),
+			_ if output.is_none() => output = Some(PathBuf::from(arg)),
+			_ => bail!("Unexpected argument: \"{}\"", arg),
+		}
+	}
+
+	if urls.is_empty() {
+		bail!("No url specified");
+	}
+	// Only an output missing from the command line altogether falls back to the environment, as it can be either
+	// a flag or positional there.
+	output = output.or_else(|| var("WUXIA_OUTPUT").ok().filter(|value| !value.is_empty()).map(PathBuf::from));
+	if quiet && verbosity > 0 {
+		bail!("\"--quiet\" can't be combined with \"--verbose\".");
+	}
+	if urls.len() > 1 {
+		if update.is_some() {
+			bail!("Only a single book can be updated at a time.");
+		}
+		if title.is_some() && !merge {
+			bail!("\"--title\" can only be given for a single book, or the one merged with \"--merge\".");
+		}
+		if manifest.is_some() && !merge {
+			bail!("\"--manifest\" can only be written for a single book, or the one merged with \"--merge\".");
+		}
+		// Split books each get their own directory inside of the output, which is created if need be, while
+		// merged ones all go into the one file.
+		if !split && !merge && output.as_ref().is_some_and(|path| !path.is_dir()) {
+			bail!("The output must be a directory when downloading several books.");
+		}
+	}
+
+	if outlier_fraction.is_some() && !flag_outliers {
+		bail!("\"--outlier-fraction\" needs \"--flag-outliers\" to apply to.");
+	}
+	if toc_depth.is_some() && !toc_page {
+		bail!("\"--toc-depth\" needs a \"--toc-page\" to apply to.");
+	}
+	if toc_page && format != OutputFormat::Epub {
+		bail!("Only epubs can have a \"--toc-page\".");
+	}
+	// The chapters of merged books are renamed after the books they're from, so there's nothing to link to up front.
+	if toc_page && merge {
+		bail!("\"--toc-page\" can't be combined with \"--merge\".");
+	}
+	let toc_page = if toc_page {
+		Some(toc_depth.unwrap_or(DEFAULT_TOC_DEPTH))
+	} else {
+		None
+	};
+
+	if series_index.is_some() && series.is_none() {
+		bail!("\"--series-index\" needs a \"--series\" to be an index into.");
+	}
+	if !meta.is_empty() && (format != OutputFormat::Epub || split) {
+		bail!("Only epubs can be given \"--meta\".");
+	}
+	if compression.is_some() && (format != OutputFormat::Epub || split) {
+		bail!("Only epubs are compressed, so \"--compression\" can't be given for other formats.");
+	}
+	if manifest.is_some() && format == OutputFormat::Json && !with_content && !split {
+		bail!("\"--manifest\" hashes the chapters' content, which the JSON export only fetches with \"--with-content\".");
+	}
+
+	if merge {
+		if format != OutputFormat::Epub {
+			bail!("Only epubs can be merged with \"--merge\".");
+		}
+		if split || update.is_some() {
+			bail!("\"--merge\" can't be combined with \"--split\" or \"--update\".");
+		}
+		if title.is_none() {
+			bail!("\"--merge\" needs a \"--title\" for the merged book.");
+		}
+	}
+
+	if let Some(ref path) = update {
+		if since.is_some() {
+			bail!("\"--update\" already only fetches the new chapters, and can't be combined with \"--since\".");
+		}
+		if split {
+			bail!("\"--update\" can't be combined with \"--split\".");
+		}
+		if format != OutputFormat::Epub {
+			bail!("Only epubs can be updated with \"--update\".");
+		}
+		// The updated epub replaces the existing one, unless told to go elsewhere.
+		output = output.or_else(|| Some(path.clone()));
+	}
+
+	Ok(Options {
+		urls,
+		output,
+		chapters,
+		limit,
+		jobs,
+		retries,
+		cache_dir,
+		no_cache,
+		clear_cache,
+		format,
+		sort,
+		strict_duplicates,
+		strict_sequence,
+		user_agent,
+		read_timeout,
+		connect_timeout,
+		delay,
+		dry_run,
+		verbosity,
+		title_page,
+		css,
+		lang,
+		with_content,
+		update,
+		cover,
+		generate_cover,
+		validate,
+		strip_boilerplate,
+		flag_outliers: Some(outlier_fraction.unwrap_or(DEFAULT_OUTLIER_FRACTION)).filter(|_| flag_outliers),
+		proxy,
+		danger_accept_invalid_certs,
+		config,
+		split,
+		force,
+		plain,
+		no_progress,
+		quiet,
+		skip_failed,
+		merge,
+		title,
+		min_chars,
+		exclude,
+		source_links,
+		abort_after,
+		since,
+		title_format,
+		uuid,
+		series,
+		series_index,
+		max_chapter_bytes,
+		authorization,
+		cookie,
+		cookie_file,
+		toc_page,
+		pretty_xhtml,
+		offline,
+		manifest,
+		meta,
+		normalize_punct,
+		compression,
+	})
+}
+
+fn parse_seconds(value: &str) -> Result<Duration> {
+	let seconds = value.parse::<u64>()
+					   .chain_err(|| format!("Unable to parse seconds \"{}\"", value))?;
+	if seconds == 0 {
+		bail!("Timeouts must be at least 1 second.");
+	}
+	Ok(Duration::from_secs(seconds))
+}
+
+/// Checks that `value` looks like a BCP 47 tag (a 2-3 letter primary language, optionally followed by subtags
+/// such as a region), lowercasing the primary language.
+fn parse_lang(value: &str) -> Result<String> {
+	let mut subtags = value.split('-');
+	let primary = subtags.next().unwrap_or_default();
+	if !(2..=3).contains(&primary.len()) || !primary.chars().all(|c| c.is_ascii_alphabetic()) {
+		bail!("Invalid language \"{}\": expected a 2 or 3 letter code such as \"en\" or \"zh\"", value);
+	}
+	if !subtags.all(|subtag| (1..=8).contains(&subtag.len()) && subtag.chars().all(|c| c.is_ascii_alphanumeric())) {
+		bail!("Invalid language \"{}\": malformed subtag", value);
+	}
+
+	let mut lang = primary.to_ascii_lowercase();
+	lang.push_str(&value[primary.len()..]);
+	Ok(lang)
+}
+
+fn parse_proxy(value: &str) -> Result<Url> {
+	let proxy = value.parse::<Url>()
+					 .chain_err(|| format!("Invalid proxy URL: \"{}\"", value))?;
+	match proxy.scheme() {
+		"http" | "https" | "socks5" | "socks5h" => Ok(proxy),
+		scheme => bail!("Unsupported proxy scheme \"{}\", expected http, https, socks5 or socks5h", scheme),
+	}
+}
+
+fn next_value<'a>(args: &mut impl Iterator<Item = &'a String>, arg: &str) -> Result<&'a String> {
+	args.next()
+		.chain_err(|| format!("Missing value for \"{}\"", arg))
+}
+
+/// Resolves the destination of the output, falling back to `<title>.<extension>` inside of the given directory (or the current one).
+fn resolve_output_path(output: Option<PathBuf>, title: &str, extension: &str) -> PathBuf {
+	let file_name = format!("{}.{}", sanitize_filename(title), extension);
+	match output {
+		Some(ref path) if path.is_dir() => path.join(file_name),
+		Some(path) => path,
+		None => PathBuf::from(file_name),
+	}
+}
+
+/// What the output is named after, unless given: the title, marked as only holding the later chapters with `--since`.
+fn output_name(options: &Options, info: &BookInfo) -> String {
+	match options.since {
+		Some(since) => format!("{} (after chapter {})", info.title, since),
+		None => info.title.clone(),
+	}
+}
+
+/// Resolves the directory written to with `--split`, named after the title unless given (in which case it holds
+/// a directory per book when downloading several).
+fn resolve_split_dir(output: Option<PathBuf>, title: &str, several: bool) -> PathBuf {
+	match output {
+		Some(dir) if several => dir.join(sanitize_filename(title)),
+		Some(dir) => dir,
+		None => PathBuf::from(sanitize_filename(title)),
+	}
+}
+
+/// Asks `question` of whoever's at the terminal, refusing without asking when there's no one to ask.
+fn confirm_overwrite(question: &str) -> Result<bool> {
+	if !stdin().is_terminal() {
+		return Ok(false);
+	}
+
+	eprint!("{} [y/N] ", question);
+	stderr().flush()
+			.chain_err(|| "Unable to prompt for confirmation.")?;
+	let mut answer = String::new();
+	stdin().read_line(&mut answer)
+		   .chain_err(|| "Unable to read confirmation.")?;
+	Ok(matches!(answer.trim().to_ascii_lowercase().as_str(), "y" | "yes"))
+}
+
+/// Builds the client shared by the book info and chapter requests.
+fn build_client(options: &Options) -> Result<Client> {
+	let mut headers = HeaderMap::new();
+	let user_agent = HeaderValue::from_str(&options.user_agent)
+		.chain_err(|| format!("Invalid user agent: \"{}\"", options.user_agent))?;
+	headers.insert(USER_AGENT, user_agent);
+	headers.insert(ACCEPT_ENCODING, HeaderValue::from_static(ACCEPT_ENCODINGS));
+	if let Some(ref authorization) = options.authorization {
+		// The credentials are left out of the error, as they'd end up in the terminal.
+		let mut value = HeaderValue::from_str(authorization)
+			.chain_err(|| "Invalid credentials: they can't be sent in a header.")?;
+		value.set_sensitive(true);
+		headers.insert(AUTHORIZATION, value);
+	}
+
+	let builder = Client::builder()
+		.default_headers(headers)
+		.gzip(true)
+		.timeout(options.read_timeout)
+		.connect_timeout(options.connect_timeout);
+
+	let builder = if options.danger_accept_invalid_certs {
+		warn!("Certificates aren't being checked (\"--danger-accept-invalid-certs\"), so anyone between you and the site can read and change everything that's downloaded.");
+		builder.danger_accept_invalid_certs(true)
+	} else {
+		builder
+	};
+
+	// Without an explicit proxy, HTTP_PROXY and HTTPS_PROXY are honoured.
+	let builder = match options.proxy {
+		Some(ref proxy) => {
+			let proxy = Proxy::all(proxy.as_str())
+				.chain_err(|| format!("Unable to use proxy \"{}\"", proxy))?;
+			builder.proxy(proxy)
+		}
+		None => builder.use_sys_proxy(),
+	};
+
+	builder.build()
+		   .chain_err(|| "Unable to construct HTTP client.")
+}
+
+/// Prints the book's details followed by one `<index>\t<title>` line per chapter, for `--dry-run`.
+fn print_chapter_list(info: &BookInfo) {
+	println!("Title: {}", info.title);
+	println!("Author: {}", info.author.as_ref().map_or("Unknown", String::as_str));
+	if !info.tags.is_empty() {
+		println!("Tags: {}", info.tags.join(", "));
+	}
+	println!("Chapters: {}", info.chapters.len());
+	for chapter in &info.chapters {
+		println!("{}\t{}", chapter.index, chapter.title);
+	}
+}
+
+/// The cookies of the session given with `--cookie` (for the host of `url`) or `--cookie-file`.
+///
+/// Meant for chapters the user is entitled to read, like the ones they've subscribed to, which the site only
+/// shows once they're signed in.
+fn cookie_jar(options: &Options, url: &Url) -> Result<CookieJar> {
+	let jar = CookieJar::new();
+	if let Some(ref path) = options.cookie_file {
+		jar.read_netscape_file(path)?;
+	}
+	if let Some(ref cookie) = options.cookie {
+		jar.add_header(url.host_str().unwrap_or_default(), cookie)?;
+	}
+	Ok(jar)
+}
+
+/// Fetches the book info at `url` and narrows its chapters down to the ones selected.
+fn inspect(options: &Options, url: &str, progress: &Progress) -> Result<(Box<dyn Source>, BookInfo, FetchOptions, Gaps)> {
 	let url = url.parse::<Url>()
 				 .chain_err(|| format!("Unable to parse URL: \"{}\"", url))?;
+	if !matches!(url.scheme(), "http" | "https") {
+		bail!("Unsupported URL \"{}\": expected an http or https link to a book.", url);
+	}
+	// Checked before anything is fetched, as another site's pages would only turn up empty.
+	adapter_for(&url)?;
+
+	// Saved pages don't change between attempts, and there's no server to be polite to.
+	let (source, retries, delay): (Box<dyn Source>, _, _) = match options.offline {
+		Some(ref dir) => (Box::new(OfflineSource::new(dir.clone(), url.clone())), 0, Duration::from_millis(0)),
+		None => (Box::new(HttpSource::new(build_client(options)?, cookie_jar(options, &url)?)), options.retries, options.delay),
+	};
+
+	let cache_dir = match options.cache_dir {
+		_ if options.no_cache || options.offline.is_some() => None,
+		Some(ref dir) => Some(dir.clone()),
+		None => dirs::cache_dir().map(|dir| dir.join("wuxia-dl")),
+	};
+	let fetch = FetchOptions {
+		retries,
+		cache_dir: cache_dir.map(|dir| dir.join(cache_key(&url))),
+		rate_limiter: RateLimiter::new(delay),
+		proxy: options.proxy.clone(),
+		existing: HashMap::new(),
+		plain: options.plain,
+		punctuation: options.normalize_punct,
+		skip_failed: options.skip_failed,
+		min_content_chars: options.min_chars,
+		interrupted: &INTERRUPTED,
+		abort_after: options.abort_after,
+		consecutive_failures: AtomicUsize::new(0),
+		failed: Mutex::new(Vec::new()),
+		title_format: options.title_format.clone(),
+		max_chapter_bytes: options.max_chapter_bytes,
+		words: AtomicUsize::new(0),
+		chapter_words: Mutex::new(Vec::new()),
+		downloads: Some(progress.multi.clone()),
+	};
+
+	info!("Inspecting \"{}\"...", url);
+	progress.phase("TOC", "Fetching TOC");
+	let mut info: BookInfo = fetch_book_info(source.as_ref(), url.clone(), &fetch, &progress.phase)
+		.chain_err(|| "Unable to fetch book info.")?;
+	info!("Found \"{}\" with {} chapters.", info.title, info.chapters.len());
+
+	check_duplicates(&mut info.chapters, options.strict_duplicates)?;
+
+	if options.sort {
+		// Some book pages list the newest chapters first, or group them by volume.
+		info.chapters.sort_by_key(|chapter| chapter.index);
+	}
+	// Found before any are deselected, so excluded chapters don't count as missing.
+	let gaps = find_gaps(&info.chapters);
+
+	if !options.exclude.is_empty() {
+		let before = info.chapters.len();
+		info.chapters.retain(|chapter| !options.exclude.iter().any(|pattern| pattern.is_match(&chapter.title)));
+		info!("Excluded {} chapters.", before - info.chapters.len());
+		if info.chapters.is_empty() {
+			bail!("Every chapter of \"{}\" was excluded.", info.title);
+		}
+	}
+
+	if let Some(since) = options.since {
+		info.chapters.retain(|chapter| chapter.index > since);
+		if info.chapters.is_empty() {
+			bail!("\"{}\" has no chapters after chapter {}.", info.title, since);
+		}
+		info!("Selected {} chapters after chapter {}.", info.chapters.len(), since);
+	}
+
+	if let Some(range) = options.chapters {
+		info.chapters.retain(|chapter| range.contains(chapter.index));
+		if info.chapters.is_empty() {
+			bail!("No chapters of \"{}\" fall within the selected range.", info.title);
+		}
+		info!("Selected {} chapters.", info.chapters.len());
+	}
+
+	// Unlike the range, the limit is positional: the first N chapters, whatever their indices.
+	if let Some(limit) = options.limit {
+		if info.chapters.len() > limit {
+			info.chapters.truncate(limit);
+			info!("Limited to the first {} chapters.", limit);
+		}
+	}
+
+	// Only the chapters missing among the selected ones matter, not those missing from the rest of the book.
+	let numbered = info.chapters.iter().filter(|chapter| chapter.numbered).map(|chapter| chapter.index);
+	let gaps = match (numbered.clone().min(), numbered.max()) {
+		(Some(first), Some(last)) => gaps_within(&gaps, first, last),
+		_ => Vec::new(),
+	};
+
+	Ok((source, info, fetch, gaps))
+}
+
+/// Downloads the book at `url`, prefixing the labels of its progress bars with its `position` among the books.
+fn run(options: &Options, url: &str, position: &str) -> Result<()> {
+	let progress = Progress::new(options, position)?;
+	let (source, mut info, mut fetch, mut gaps) = inspect(options, url, &progress)?;
+	if interrupted() {
+		bail!("Interrupted before fetching any chapters.");
+	}
+	// Used for the metadata and the file name alike, while the scraped title is still logged for reference.
+	if let Some(ref title) = options.title {
+		info!("Titling the book \"{}\" instead of \"{}\".", title, info.title);
+		info.title = title.clone();
+	}
+
+	let (existing_pages, existing_images, added) = match options.update {
+		Some(ref path) => {
+			let existing = read_existing_epub(path)?;
+			let highest = existing.highest_index();
+			// Chapters already in the epub are carried over, while anything new has to come after them.
+			info.chapters.retain(|chapter| {
+				existing.pages.contains_key(&chapter.file_stem()) || highest.is_none_or(|highest| chapter.index > highest)
+			});
+
+			let added = info.chapters.iter()
+									 .filter(|chapter| !existing.pages.contains_key(&chapter.file_stem()))
+									 .count();
+			if added == 0 {
+				info!("\"{}\" is already up to date.", path.display());
+				return Ok(());
+			}
+			info!("Found {} new chapters since chapter {}.", added, highest.unwrap_or(0));
+			// The chapters missing from what's already in the epub were warned about when it was downloaded.
+			if let Some(highest) = highest {
+				gaps = gaps_within(&gaps, highest + 1, u32::MAX);
+			}
+			(existing.pages, existing.images, Some(added))
+		}
+		None => (HashMap::new(), Vec::new(), None),
+	};
+	check_gaps(&info, &gaps, options.strict_sequence)?;
+
+	if options.dry_run {
+		drop(progress);
+		print_chapter_list(&info);
+		return Ok(());
+	}
 
-	let client = Client::new();
-
-	println!("Inspecting \"{}\"...", url);
-	let info: BookInfo = fetch_book_info(&client, url)
-		.chain_err(|| format!("Unable to fetch book info."))?;
-
-	let zip = ZipLibrary::new()
-		.chain_err(|| "Unable to construct ZipLibrary.")?;
-	let mut builder: EpubBuilder<ZipLibrary> = EpubBuilder::new(zip)
-		.chain_err(|| "Unable to construct EpubBuilder")?;
-	builder.metadata("title", info.title.clone())
-		   .chain_err(|| "Unable to alter title.")?;
-	builder.metadata("toc_name", info.title.clone())
-		   .chain_err(|| "Unable to alter Table of Contents.")?;
-	builder.metadata("author", "WuxiaWorld")
-		   .chain_err(|| "Unable to set author metadata.")?;
-
-	let size = info.chapters.len();
-
-	let bar: ProgressBar = ProgressBar::new(size as u64);
-	bar.set_style(ProgressStyle::default_bar()
-		.template("[{prefix}] [{bar:40}] {pos}/{len} ({eta}) {msg}")
-		.progress_chars("=>-"));
-	bar.set_prefix("FETCH");
-	bar.set_message("");
-
-	let pages: Pages = info.chapters
-						   .into_par_iter()
-						   .map(|chapter| {
-							   fetch_chapter_content(&client, chapter, &bar)
-								   .chain_err(|| "Unable to fetch chapter content")
-								   .unwrap()
-						   })
-						   .collect();
-
-	let path = format!("{}.epub", info.title);
-	let path = Path::new(&path);
-
-	bar.set_prefix("EPUB");
-	bar.set_message("");
-	bar.set_position(0);
-
-	for page in pages {
-		bar.inc(1);
-		builder.add_content(page)
-			   .chain_err(|| format!("Unable to add page."))?;
+	if let Some(ref dir) = fetch.cache_dir {
+		create_dir_all(dir)
+			.chain_err(|| format!("Unable to create cache directory: \"{}\"", dir.display()))?;
 	}
+	fetch.existing = existing_pages;
+
+	// A cover given on the command line beats the scraped one, which beats a generated one.
+	let cover = match (&options.cover, &info.cover_url) {
+		_ if options.format != OutputFormat::Epub || options.split => None,
+		(Some(path), _) => Some(read_image(path)?),
+		(None, Some(cover_url)) => match fetch_image(source.as_ref(), cover_url, &fetch) {
+			Ok(cover) => Some(cover),
+			Err(e) => {
+				warn!("Unable to fetch cover from \"{}\", continuing without one: {}", cover_url, e);
+				None
+			}
+		},
+		(None, None) => None,
+	};
+	let cover = match cover {
+		None if options.format == OutputFormat::Epub && !options.split && options.generate_cover => Some(generate_cover(&info)),
+		cover => cover,
+	};
+
+	// Without its content, the JSON export is written straight from the book info.
+	let fetch_content = options.format != OutputFormat::Json || options.with_content || options.split;
+
+	let bar = progress.chapters(info.chapters.len(), fetch_content)?;
+	let mut manifest = Vec::new();
+
+	let format = options.format;
+	if options.split {
+		let dir = resolve_split_dir(options.output.clone(), &output_name(options, &info), options.urls.len() > 1);
+		confirm_replace_split(options, &dir, &info.chapters, format.page_extension(), &progress)?;
+		create_dir_all(&dir)
+			.chain_err(|| format!("Unable to create output directory: \"{}\"", dir.display()))?;
+
+		// Split chapters leave their images where they are, as there's no epub to embed them into.
+		let result = fetch_chapters(source.as_ref(), &info.chapters, &fetch, None, &bar, options.jobs, |pages| {
+			let pages = without_boilerplate(pages, options.strip_boilerplate)?.inspect(|page| record_manifest(options, &mut manifest, page));
+			write_split(pages, &dir, format.page_extension(), |page| format.render_page(page, options.source_links, options.pretty_xhtml))
+		});
+
+		bar.finish();
+		drop(progress);
+
+		let written = result?;
+		info!("Wrote {} chapters to \"{}\" for \"{}\"", written, dir.display(), info.title);
+		if let Some(ref path) = options.manifest {
+			write_manifest_file(path, &info, &manifest)?;
+		}
+		log_word_count(fetch.words.load(Ordering::SeqCst), written);
+		flag_outliers(options.flag_outliers, &fetch, &info.title);
+		if interrupted() {
+			bail!("Interrupted, only the chapters fetched so far were written.");
+		}
+		clear_cache(options, &fetch)?;
+		return check_failed(&fetch);
+	}
+
+	let images = if options.format.embeds_images() {
+		Some(ImageStore::with_images(existing_images))
+	} else {
+		None
+	};
+
+	let epub_options = epub_options(options)?;
+	let extension = format.extension();
+	let path = resolve_output_path(options.output.clone(), &output_name(options, &info), extension);
+
+	// An update replaces the existing epub by design.
+	if options.update.is_none() {
+		confirm_replace(options, &path, &progress)?;
+	}
+	let part_path = part_path(&path);
+	let file = File::create(&part_path)
+		.chain_err(|| format!("Unable to create file: \"{}\"", part_path.display()))?;
+
+	// Pages are written as soon as they're fetched, rather than holding the whole book in memory.
+	let result = if fetch_content {
+		fetch_chapters(source.as_ref(), &info.chapters, &fetch, images.as_ref(), &bar, options.jobs, |pages| {
+			// Whatever's left once the last chapter is in happens after the chapters' bar is done.
+			let pages = without_boilerplate(pages, options.strip_boilerplate)?
+				.inspect(|page| record_manifest(options, &mut manifest, page))
+				.chain(iter::from_fn(|| {
+					bar.finish();
+					let (label, message) = format.finishing_phase();
+					progress.phase(label, message);
+					None
+				}));
+			format.write_book(&info, &epub_options, cover, images.as_ref(), pages, PhaseWriter::new(file, &progress))
+		})
+	} else {
+		write_json(&info, iter::empty(), BufWriter::new(file))
+	};
 
 	bar.finish();
 
-	if path.exists() {
-		println!("File (\"{}\") already exists. Deleting previous epub...", path.display());
-		remove_file(path)
-			.chain_err(|| format!("Failed to remove previous file: \"{}\"", path.display()))?;
+	if let Err(e) = result {
+		// Don't leave a truncated file behind.
+		let _ = remove_file(&part_path);
+		return Err(e);
 	}
-	let file = File::create(path)
-		.chain_err(|| format!("Unable to create file: \"{}\"", path.display()))?;
-	builder.generate(file)
-		   .chain_err(|| "Unable to generate epub")?;
+	let checked = if options.validate && format == OutputFormat::Epub && !interrupted() {
+		Some(validate_part(&part_path, &path, &progress)?)
+	} else {
+		None
+	};
+	let path = if interrupted() {
+		partial_path(&path)
+	} else {
+		path
+	};
+	let path = move_into_place(&part_path, &path)?;
+	if let Some(ref manifest_path) = options.manifest {
+		write_manifest_file(manifest_path, &info, &manifest)?;
+	}
+	if interrupted() {
+		bail!("Interrupted, the chapters fetched so far were saved to \"{}\".", path.display());
+	}
+	if let Some(checked) = checked {
+		info!("Validated {} documents in \"{}\".", checked, path.display());
+	}
+
+	drop(progress);
+	match added {
+		Some(added) => info!("Added {} new chapters to \"{}\" ({})", added, path.display(), file_size(&path)),
+		None => info!("Generated {} file @ \"{}\" for \"{}\" ({})", extension, path.display(), info.title, file_size(&path)),
+	}
+	log_word_count(fetch.words.load(Ordering::SeqCst), info.chapters.len());
+	flag_outliers(options.flag_outliers, &fetch, &info.title);
+
+	clear_cache(options, &fetch)?;
+	check_failed(&fetch)
+}
+
+/// Most ranges of missing chapters listed, so a book numbered in some unusual way doesn't flood the output.
+const MAX_LISTED_GAPS: usize = 20;
 
-	println!("Generated epub file @ \"{}\" for \"{}\"", path.display(), info.title);
+/// Runs of chapter numbers missing from a book, as found by `find_gaps`.
+type Gaps = Vec<(u32, u32)>;
+
+/// The parts of `gaps` (see `find_gaps`) between chapters `first` and `last`.
+fn gaps_within(gaps: &[(u32, u32)], first: u32, last: u32) -> Gaps {
+	gaps.iter()
+		.map(|&(start, end)| (start.max(first), end.min(last)))
+		.filter(|&(start, end)| start <= end)
+		.collect()
+}
+
+/// Warns about the chapters in `gaps` missing from the numbering of the book, deleted or not yet posted, or
+/// with `--strict-sequence`, refuses to go on without them.
+fn check_gaps(info: &BookInfo, gaps: &[(u32, u32)], strict: bool) -> Result<()> {
+	if gaps.is_empty() {
+		return Ok(());
+	}
+
+	let missing: u32 = gaps.iter().map(|&(start, end)| end - start + 1).sum();
+	let mut listed: Vec<String> = gaps.iter()
+									  .take(MAX_LISTED_GAPS)
+									  .map(|&(start, end)| if start == end { start.to_string() } else { format!("{}-{}", start, end) })
+									  .collect();
+	if gaps.len() > MAX_LISTED_GAPS {
+		listed.push(format!("and {} more", gaps.len() - MAX_LISTED_GAPS));
+	}
 
+	if strict {
+		bail!("\"{}\" is missing {} chapters: {}", info.title, missing, listed.join(", "));
+	}
+	warn!("\"{}\" is missing {} chapters, which will be skipped: {}", info.title, missing, listed.join(", "));
 	Ok(())
 }
 
-fn fetch_book_info(client: &Client, url: Url) -> Result<BookInfo> {
-	let req = client.get(url)
-					.build()
-					.chain_err(|| "Unable to construct book info request.")?;
-	let mut res = client.execute(req)
-						.chain_err(|| "Unable to execute book info request.")?;
+/// Adds `page` to the `--manifest`, if there's one to be written.
+fn record_manifest(options: &Options, manifest: &mut Vec<ManifestEntry>, page: &Result<Page>) {
+	if let (Some(_), Ok(page)) = (&options.manifest, page) {
+		manifest.push(page.manifest_entry());
+	}
+}
 
-	let chapter_regex = Regex::new(r".+?(\d+)[- ]*(.*)")
-		.chain_err(|| "Unable to construct regex.")?;
+/// Writes the `--manifest` of `entries` to `path`.
+fn write_manifest_file(path: &Path, info: &BookInfo, entries: &[ManifestEntry]) -> Result<()> {
+	let file = File::create(path)
+		.chain_err(|| format!("Unable to create manifest: \"{}\"", path.display()))?;
+	write_manifest(info, entries, BufWriter::new(file))?;
+	info!("Wrote a manifest of {} chapters to \"{}\"", entries.len(), path.display());
+	Ok(())
+}
 
-	let doc = Document::from_read(&mut res)
-		.chain_err(|| "Unable to construct document from response.")?;
+/// Logs how many words were downloaded across `chapters` chapters, if any were (the JSON export only fetches
+/// chapters with `--with-content`).
+fn log_word_count(words: usize, chapters: usize) {
+	if let Some(average) = words.checked_div(chapters).filter(|_| words > 0) {
+		info!("Downloaded {} words, averaging {} per chapter.", format_word_count(words), average);
+	}
+}
 
-	let url = res.url();
+/// Warns about the chapters of `book` with less than `fraction` of the median chapter's words, for
+/// `--flag-outliers`, as those are often a teaser or paywall stub standing in for the chapter.
+fn flag_outliers(fraction: Option<f64>, fetch: &FetchOptions, book: &str) {
+	let fraction = match fraction {
+		Some(fraction) => fraction,
+		None => return,
+	};
+	let mut chapters = fetch.chapter_words.lock().unwrap();
+	// The median of one or two chapters says nothing about what's usual.
+	if chapters.len() < 3 {
+		return;
+	}
 
-	let book_title = doc.find(Class("p-15").descendant(Name("h4"))).next()
-						.chain_err(|| "Failed to locate book title")?
-		.text();
+	chapters.sort_by_key(|&(index, _)| index);
+	let mut words: Vec<usize> = chapters.iter()
+										.map(|&(_, words)| words)
+										.collect();
+	words.sort_unstable();
+	let median = words[words.len() / 2];
+	let threshold = median as f64 * fraction;
 
+	let outliers: Vec<String> = chapters.iter()
+										.filter(|&&(_, words)| (words as f64) < threshold)
+										.map(|(index, words)| format!("{} ({} words)", index, words))
+										.collect();
+	if !outliers.is_empty() {
+		warn!("{} chapters of \"{}\" have less than {:.0}% of the median chapter's {} words, and may only be a stub of it: {}", outliers.len(), book, fraction * 100.0, median, outliers.join(", "));
+	}
+}
+
+/// The size of the file at `path`, in whichever unit reads best.
+fn file_size(path: &Path) -> String {
+	let bytes = match metadata(path) {
+		Ok(metadata) => metadata.len(),
+		Err(_) => return "unknown size".to_owned(),
+	};
+	match bytes {
+		0..=1_023 => format!("{} B", bytes),
+		1_024..=1_048_575 => format!("{:.1} KiB", bytes as f64 / 1_024.0),
+		_ => format!("{:.1} MiB", bytes as f64 / 1_048_576.0),
+	}
+}
+
+/// Shortens large counts to thousands or millions, the way "3.2M words" reads.
+fn format_word_count(words: usize) -> String {
+	match words {
+		0..=9_999 => words.to_string(),
+		10_000..=999_999 => format!("{:.1}k", words as f64 / 1_000.0),
+		_ => format!("{:.1}M", words as f64 / 1_000_000.0),
+	}
+}
+
+/// Fails the book if any of its chapters were replaced by placeholders with `--skip-failed`, after listing them.
+fn check_failed(fetch: &FetchOptions) -> Result<()> {
+	let mut failed = fetch.failed.lock().unwrap();
+	if failed.is_empty() {
+		return Ok(());
+	}
+
+	failed.sort_by_key(|&(index, _)| index);
+	for (index, reason) in failed.iter() {
+		warn!("  Chapter {}: {}", index, reason);
+	}
+	let indices: Vec<String> = failed.iter()
+									 .map(|(index, _)| index.to_string())
+									 .collect();
+	bail!("{} chapters failed to fetch and were left as placeholders: {}", failed.len(), indices.join(", "));
+}
+
+/// Downloads every book into a single epub titled with `--title`, each book nesting its chapters under its own
+/// entry of the table of contents (in place of any volumes it had).
+///
+/// Unlike a single book, the pages are only written once every book is in, as they're fetched one after another.
+fn run_merged(options: &Options) -> Result<()> {
+	let title = options.title.clone().chain_err(|| "No title given for the merged book.")?;
+	let images = ImageStore::with_images(Vec::new());
+
+	let total = options.urls.len();
+	let mut fetches = Vec::new();
 	let mut chapters = Vec::new();
-	for node in doc.find(Class("chapter-item").descendant(Name("a"))) {
-		let full_title = node.text().trim().to_owned();
-
-		let cap = chapter_regex.captures(&full_title)
-							   .chain_err(|| format!("Failed to match regex against: {}", full_title))?;
-
-		let raw_index = &cap[1];
-		let index = raw_index.parse::<u32>()
-							 .chain_err(|| format!("Unable to parse index {}", raw_index))?;
-		let title = cap[2].to_owned();
-
-		let href = node.attr("href")
-					   .chain_err(|| "No href specified")?;
-		let link = url.join(href)
-					  .chain_err(|| format!("Unable to append href (\"{}\") to url (\"{}\").", href, url))?;
-
-		chapters.push(Chapter {
-			index,
-			title,
-			link,
+	let mut pages = Vec::new();
+	let mut authors: Vec<String> = Vec::new();
+	let mut tags: Vec<String> = Vec::new();
+	for (i, url) in options.urls.iter().enumerate() {
+		let progress = Progress::new(options, &format!("{}/{} ", i + 1, total))?;
+		let (source, info, fetch, gaps) = inspect(options, url, &progress)?;
+		check_gaps(&info, &gaps, options.strict_sequence)?;
+		if interrupted() {
+			break;
+		}
+		if options.dry_run {
+			drop(progress);
+			print_chapter_list(&info);
+			continue;
+		}
+
+		if let Some(ref dir) = fetch.cache_dir {
+			create_dir_all(dir)
+				.chain_err(|| format!("Unable to create cache directory: \"{}\"", dir.display()))?;
+		}
+
+		let bar = progress.chapters(info.chapters.len(), true)?;
+		let result = fetch_chapters(source.as_ref(), &info.chapters, &fetch, Some(&images), &bar, options.jobs, |pages| {
+			without_boilerplate(pages, options.strip_boilerplate)?.collect::<Result<Vec<Page>>>()
 		});
+		bar.finish();
+		drop(progress);
+
+		// The book number keeps chapters sharing an index across books from sharing a file.
+		for mut page in result.chain_err(|| format!("Unable to fetch \"{}\".", info.title))? {
+			page.name = format!("book{}_{}", i + 1, page.name);
+			page.volume = Some(info.title.clone());
+			pages.push(page);
+		}
+		for mut chapter in info.chapters {
+			chapter.volume = Some(info.title.clone());
+			chapters.push(chapter);
+		}
+		if let Some(author) = info.author {
+			if !authors.contains(&author) {
+				authors.push(author);
+			}
+		}
+		for tag in info.tags {
+			if !tags.contains(&tag) {
+				tags.push(tag);
+			}
+		}
+		fetches.push((info.url, fetch));
+		if interrupted() {
+			break;
+		}
+	}
+
+	if options.dry_run {
+		return Ok(());
+	}
+	if fetches.is_empty() {
+		bail!("Interrupted before fetching any books.");
 	}
 
 	let info = BookInfo {
-		title: book_title,
+		title,
+		url: fetches[0].0.clone(),
+		author: if authors.is_empty() { None } else { Some(authors.join(", ")) },
+		description: None,
+		tags,
+		cover_url: None,
 		chapters,
 	};
+	let cover = match options.cover {
+		Some(ref path) => Some(read_image(path)?),
+		None if options.generate_cover => Some(generate_cover(&info)),
+		None => None,
+	};
 
-	println!("Found \"{}\" with {} chapters.", info.title, info.chapters.len());
+	let progress = Progress::new(options, "")?;
+	progress.phase("EPUB", format!("Assembling epub from {} books", fetches.len()));
+	let path = resolve_output_path(options.output.clone(), &info.title, OutputFormat::Epub.extension());
+	confirm_replace(options, &path, &progress)?;
+	let part_path = part_path(&path);
+	let file = File::create(&part_path)
+		.chain_err(|| format!("Unable to create file: \"{}\"", part_path.display()))?;
+	let manifest: Vec<ManifestEntry> = pages.iter().map(Page::manifest_entry).collect();
+	let mut epub = epub_options(options)?;
+	// Kept apart from the first book's own identifier.
+	epub.identifier = epub.identifier.or_else(|| Some(book_identifier(fetches.iter().map(|(url, _)| url))));
+	if let Err(e) = build_epub(&info, &epub, cover, Some(&images), pages.into_iter().map(Ok), PhaseWriter::new(file, &progress)) {
+		let _ = remove_file(&part_path);
+		return Err(e);
+	}
+	let checked = if options.validate && !interrupted() {
+		Some(validate_part(&part_path, &path, &progress)?)
+	} else {
+		None
+	};
+	let path = if interrupted() {
+		partial_path(&path)
+	} else {
+		path
+	};
+	let path = move_into_place(&part_path, &path)?;
+	if let Some(ref manifest_path) = options.manifest {
+		write_manifest_file(manifest_path, &info, &manifest)?;
+	}
+	if interrupted() {
+		bail!("Interrupted, the books fetched so far were saved to \"{}\".", path.display());
+	}
+	if let Some(checked) = checked {
+		info!("Validated {} documents in \"{}\".", checked, path.display());
+	}
+	drop(progress);
+	info!("Generated epub file @ \"{}\" merging {} books into \"{}\" ({})", path.display(), total, info.title, file_size(&path));
+	log_word_count(fetches.iter().map(|(_, fetch)| fetch.words.load(Ordering::SeqCst)).sum(), info.chapters.len());
+	for (url, fetch) in &fetches {
+		flag_outliers(options.flag_outliers, fetch, url.as_str());
+	}
 
-	Ok(info)
+	for (_, fetch) in &fetches {
+		clear_cache(options, fetch)?;
+	}
+	for (_, fetch) in &fetches {
+		check_failed(fetch)?;
+	}
+	Ok(())
 }
 
-macro_rules! try_with {
-    ($content:ident, $doc:ident, $target:expr) => (
-		for node in $doc.find($target) {
-			let text = node.text();
-			if text.len() == 0 {
-				continue;
-			}
-			$content.push_str(&text);
-			$content.push_str(&"<br><br> ");
-		}
-    )
+fn epub_options(options: &Options) -> Result<EpubOptions> {
+	let stylesheet = match options.css {
+		Some(ref path) => Some(read_to_string(path)
+			.chain_err(|| format!("Unable to read stylesheet: \"{}\"", path.display()))?),
+		None => None,
+	};
+	Ok(EpubOptions {
+		title_page: options.title_page,
+		stylesheet,
+		lang: options.lang.clone(),
+		source_links: options.source_links,
+		title_format: options.title_format.clone(),
+		identifier: options.uuid.clone(),
+		series: options.series.clone().map(|name| (name, options.series_index)),
+		toc_page: options.toc_page,
+		pretty_xhtml: options.pretty_xhtml,
+		meta: options.meta.clone(),
+		compression: options.compression,
+	})
 }
 
-fn fetch_chapter_content(client: &Client, chapter: Chapter, bar: &ProgressBar) -> Result<EpubContent<Cursor<String>>> {
-	let req = client.get(chapter.link)
-					.build()
-					.chain_err(|| "Unable to construct chapter request.")?;
+/// Checks that the file at `path`, if there is one, may be replaced, as allowed by `--force` or the user,
+/// hiding `progress` while asking.
+fn confirm_replace(options: &Options, path: &Path, progress: &Progress) -> Result<()> {
+	if !path.exists() {
+		return Ok(());
+	}
+	if !options.force && !progress.suspend(|| confirm_overwrite(&format!("\"{}\" already exists. Overwrite it?", path.display())))? {
+		bail!("\"{}\" already exists, pass \"--force\" to overwrite it.", path.display());
+	}
+	info!("File (\"{}\") already exists, and will be replaced.", path.display());
+	Ok(())
+}
 
-	bar.inc(1);
-	bar.set_message(&chapter.title);
+/// Like `confirm_replace`, for the files `--split` writes the chapters to in `dir`, asking once for all of them.
+fn confirm_replace_split(options: &Options, dir: &Path, chapters: &[Chapter], extension: &str, progress: &Progress) -> Result<()> {
+	let width = index_width(chapters);
+	let existing = chapters.iter()
+						   .map(|chapter| dir.join(format!("{}.{}", sanitize_filename(&chapter.padded_file_stem(width)), extension)))
+						   .filter(|path| path.exists())
+						   .count();
+	if existing == 0 {
+		return Ok(());
+	}
+	if !options.force && !progress.suspend(|| confirm_overwrite(&format!("\"{}\" already has {} of the chapters in it. Overwrite them?", dir.display(), existing)))? {
+		bail!("\"{}\" already has {} of the chapters in it, pass \"--force\" to overwrite them.", dir.display(), existing);
+	}
+	info!("{} of the chapters already exist in \"{}\", and will be replaced.", existing, dir.display());
+	Ok(())
+}
 
-	let mut res = client.execute(req)
-						.chain_err(|| "Unable to send chapter request.")?;
+/// Where the output is written until it's complete, so an existing file stays intact until then.
+fn part_path(path: &Path) -> PathBuf {
+	let mut name = path.file_name().unwrap_or_default().to_owned();
+	name.push(".part");
+	path.with_file_name(name)
+}
 
-	let doc: Document = Document::from_read(&mut res)
-		.chain_err(|| "Invalid content from request")?;
+/// Validates the epub at `part_path` before it's moved to `path`, returning how many documents were checked.
+///
+/// An epub that fails is left at `part_path` to be looked into, and whatever's at `path` is kept.
+fn validate_part(part_path: &Path, path: &Path, progress: &Progress) -> Result<usize> {
+	progress.phase("CHECK", "Validating epub");
+	validate_epub(part_path)
+		.chain_err(|| format!("Validation of \"{}\" failed, the output is left at \"{}\".", path.display(), part_path.display()))
+}
 
-	let mut content = String::new();
-	try_with!(content, doc, Class("innerContent").and(Class("fr-view")).descendant(Name("p")));
-	if content.len() == 0 {
-		try_with!(content, doc, Class("fr-view").child(Name("p")));
-		if content.len() == 0 {
-			try_with!(content, doc, Class("fr-view").descendant(Name("span")));
+/// Moves the finished output at `part_path` to `path`, returning where it ended up.
+///
+/// Should the existing file be locked (by a reader, say), the output is saved as `<name> (1).<extension>`,
+/// or whichever number is free, rather than thrown away.
+fn move_into_place(part_path: &Path, path: &Path) -> Result<PathBuf> {
+	let error = match rename(part_path, path) {
+		Ok(()) => return Ok(path.to_owned()),
+		// Windows won't rename onto an existing file, which moving it aside first can still get around.
+		Err(e) if matches!(e.kind(), io::ErrorKind::AlreadyExists | io::ErrorKind::PermissionDenied | io::ErrorKind::ResourceBusy) => {
+			match replace_aside(part_path, path) {
+				Ok(()) => return Ok(path.to_owned()),
+				Err(_) => e,
+			}
 		}
-	}
-	if content.len() == 0 {
-		panic!("Discovered no content for \"Chapter {} - {}\"", chapter.index, chapter.title);
-	}
+		Err(e) => e,
+	};
 
-	let name = format!("chapter_{}.xhtml", chapter.index);
-	let chapter_title = format!("Chapter {}", chapter.index);
+	let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+	let extension = path.extension().map(|extension| format!(".{}", extension.to_string_lossy())).unwrap_or_default();
+	let fallback = (1..).map(|i| path.with_file_name(format!("{} ({}){}", stem, i, extension)))
+						.find(|fallback| !fallback.exists())
+						.chain_err(|| "Ran out of file names.")?;
+	rename(part_path, &fallback)
+		.chain_err(|| format!("Unable to save the output to \"{}\" or \"{}\", it's left at \"{}\".", path.display(), fallback.display(), part_path.display()))?;
+	warn!("Unable to replace \"{}\" ({}), so it was saved as \"{}\" instead.", path.display(), error, fallback.display());
+	Ok(fallback)
+}
 
-	let cursor = Cursor::new(content);
+/// Replaces the file at `path` with the one at `part_path` by moving it out of the way to `<name>.bak` first,
+/// putting it back should the output still not move into its place.
+fn replace_aside(part_path: &Path, path: &Path) -> io::Result<()> {
+	let mut name = path.file_name().unwrap_or_default().to_owned();
+	name.push(".bak");
+	let backup = path.with_file_name(name);
+	rename(path, &backup)?;
+	if let Err(e) = rename(part_path, path) {
+		let _ = rename(&backup, path);
+		return Err(e);
+	}
+	let _ = remove_file(&backup);
+	Ok(())
+}
 
-	Ok(EpubContent::new(name, cursor)
-		.title(chapter_title)
-		.reftype(ReferenceType::Text))
+/// Strips the boilerplate from the pages when `--strip-boilerplate` is given.
+///
+/// Boilerplate can only be told apart once every chapter is in, so stripping it gives up on streaming.
+fn without_boilerplate<'a>(pages: OrderedPages<'a>, threshold: Option<f64>) -> Result<Box<dyn Iterator<Item = Result<Page>> + 'a>> {
+	match threshold {
+		Some(threshold) => {
+			let mut pages = pages.collect::<Result<Vec<Page>>>()?;
+			strip_boilerplate(&mut pages, threshold);
+			Ok(Box::new(pages.into_iter().map(Ok)))
+		}
+		None => Ok(Box::new(pages)),
+	}
 }
 
-#[derive(Debug)]
-struct BookInfo {
-	title: String,
-	chapters: Vec<Chapter>,
+/// Removes the book's cache directory when `--clear-cache` is given.
+fn clear_cache(options: &Options, fetch: &FetchOptions) -> Result<()> {
+	if options.clear_cache {
+		if let Some(ref dir) = fetch.cache_dir {
+			remove_dir_all(dir)
+				.chain_err(|| format!("Unable to clear cache directory: \"{}\"", dir.display()))?;
+		}
+	}
+	Ok(())
 }
 
-#[derive(Debug)]
-struct Chapter {
-	index: u32,
-	title: String,
-	link: Url,
-}
\ No newline at end of file
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::env::temp_dir;
+	use std::fs::write;
+	use std::process;
+
+	#[test]
+	fn replacing_aside_keeps_the_original_if_the_output_cant_move() {
+		let dir = temp_dir().join(format!("wuxia-dl-test-replace-{}", process::id()));
+		create_dir_all(&dir).unwrap();
+		let path = dir.join("book.epub");
+		write(&path, "old").unwrap();
+
+		assert!(replace_aside(&part_path(&path), &path).is_err());
+		assert_eq!(read_to_string(&path).unwrap(), "old");
+
+		write(part_path(&path), "new").unwrap();
+		replace_aside(&part_path(&path), &path).unwrap();
+		assert_eq!(read_to_string(&path).unwrap(), "new");
+		assert!(!part_path(&path).exists() && !dir.join("book.epub.bak").exists());
+		let _ = remove_dir_all(&dir);
+	}
+
+	#[test]
+	fn an_interrupted_merge_stops_fetching_books() {
+		let dir = temp_dir().join(format!("wuxia-dl-test-merge-{}", process::id()));
+		create_dir_all(&dir).unwrap();
+		write(dir.join("index.html"), r#"<html><body><div class="p-15"><h4>Foo Book</h4></div>
+<ul><li class="chapter-item"><a href="/novel/foo/chapter-1">Chapter 1 - Start</a></li></ul></body></html>"#).unwrap();
+		write(dir.join("chapter-1.html"), r#"<html><body><div class="fr-view"><p>Words here.</p></div></body></html>"#).unwrap();
+		let output = dir.join("merged.epub");
+		let args: Vec<String> = ["--merge", "--title", "Merged", "--offline", dir.to_str().unwrap(), "--no-cache", "--quiet", "--no-progress", "--force", "--output", output.to_str().unwrap(),
+								 "https://www.wuxiaworld.com/novel/foo", "https://www.wuxiaworld.com/novel/bar"]
+			.iter()
+			.map(|arg| arg.to_string())
+			.collect();
+		let options = parse_args(&args).unwrap();
+
+		INTERRUPTED.store(true, Ordering::SeqCst);
+		let result = run_merged(&options);
+		INTERRUPTED.store(false, Ordering::SeqCst);
+
+		let error = result.unwrap_err();
+		assert!(error.to_string().contains("Interrupted"), "{}", error);
+		assert!(!output.exists() && !part_path(&output).exists());
+		let _ = remove_dir_all(&dir);
+	}
+}
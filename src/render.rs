@@ -0,0 +1,927 @@
+use chrono::Utc;
+use epub_builder::EpubBuilder;
+use epub_builder::EpubContent;
+use epub_builder::ReferenceType;
+use epub_builder::TocElement;
+use epub_builder::ZipLibrary;
+use select::document::Document;
+use select::predicate::{Name, Predicate};
+use super::errors::*;
+use super::{BookInfo, Image, ImageStore, ManifestEntry, Page, escape_html, image_extension, index_width, sanitize_filename};
+use std::fs::write;
+use std::io::{Cursor, Read, Write};
+use std::path::Path;
+use url::Url;
+use uuid::Uuid;
+use zip::ZipArchive;
+use zip::CompressionMethod;
+use zip::write::{FileOptions, ZipWriter};
+
+/// Styles every page of the epub unless replaced through `EpubOptions::stylesheet`.
+const DEFAULT_STYLESHEET: &str = "body {
+	margin: 0 5%;
+	line-height: 1.5;
+}
+
+h1 {
+	margin: 1em 0 1.5em;
+	text-align: center;
+}
+
+p {
+	margin: 0 0 0.8em;
+	text-indent: 0;
+}
+
+hr.scene-break {
+	margin: 1.5em 25%;
+	border: 0;
+	border-top: 1px solid #888;
+}
+
+span.underline {
+	text-decoration: underline;
+}
+
+div.source-link {
+	margin-top: 2em;
+	font-size: 0.75em;
+	text-align: right;
+}
+
+div.source-link a {
+	color: #888;
+}
+
+div.image {
+	margin: 1em 0;
+	text-align: center;
+}
+
+div.image img {
+	max-width: 100%;
+}
+
+div.title-page {
+	margin-top: 20%;
+	text-align: center;
+}
+
+div.title-page img {
+	max-width: 80%;
+	max-height: 50%;
+}
+
+ol.contents, ol.contents ol {
+	list-style: none;
+	padding-left: 1em;
+}
+";
+
+/// Optional parts of the generated epub.
+pub struct EpubOptions {
+	/// Whether to start the book with a generated title page.
+	pub title_page: bool,
+	/// CSS used in place of the default stylesheet.
+	pub stylesheet: Option<String>,
+	/// The BCP 47 language tag of the content.
+	pub lang: String,
+	/// Whether to end every chapter with a link to where it was fetched from.
+	pub source_links: bool,
+	/// What chapters are headed with, see `Chapter::page_title`.
+	pub title_format: String,
+	/// The `dc:identifier` used in place of the one `book_identifier` derives from the book's URL.
+	pub identifier: Option<String>,
+	/// The series the book belongs to, as Calibre groups them, along with where in the series it falls.
+	pub series: Option<(String, Option<f64>)>,
+	/// How deep the contents page following the title page goes, if there is one: 1 lists only the chapters, while
+	/// 2 groups them under their volumes too. For readers that make a mess of the nested navigation.
+	pub toc_page: Option<usize>,
+	/// Whether the XHTML documents are indented, see `pretty_xhtml`, for proofreading them.
+	pub pretty_xhtml: bool,
+	/// Metadata given with `--meta`, see `parse_meta`, which replaces whatever was scraped for the same key.
+	pub meta: Vec<(String, String)>,
+	/// How the entries are compressed instead of `EpubBuilder`'s default deflate, if at all.
+	pub compression: Option<Compression>,
+}
+
+/// How hard the entries of the epub are compressed, trading the time it takes for its size.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Compression {
+	/// Not compressed at all.
+	Stored,
+	/// Deflated at the fastest level.
+	Fast,
+	/// Deflated at the smallest level.
+	Best,
+}
+
+impl Compression {
+	fn file_options(self) -> FileOptions {
+		match self {
+			Compression::Stored => FileOptions::default().compression_method(CompressionMethod::Stored),
+			Compression::Fast => FileOptions::default().compression_method(CompressionMethod::Deflated).compression_level(Some(1)),
+			Compression::Best => FileOptions::default().compression_method(CompressionMethod::Deflated).compression_level(Some(9)),
+		}
+	}
+}
+
+/// The `--meta` keys `EpubBuilder` sets itself, along with the name it knows each by.
+const BUILDER_META: &[(&str, &str)] = &[
+	("title", "title"),
+	("author", "author"),
+	("creator", "author"),
+	("lang", "lang"),
+	("language", "lang"),
+	("description", "description"),
+	("subject", "subject"),
+	("rights", "license"),
+	("license", "license"),
+	("generator", "generator"),
+];
+
+/// The rest of the Dublin Core elements, which are added to the package document instead.
+const DUBLIN_CORE_META: &[&str] = &["contributor", "coverage", "date", "format", "publisher", "relation", "source", "type"];
+
+/// Parses a `--meta` of the form `KEY=VALUE`.
+///
+/// The key is either a Dublin Core element (`rights`, `contributor`, `publisher`, ..., optionally written with its
+/// `dc:` prefix), or any other prefixed name like `calibre:rating`, which is added as a `<meta name="..."/>`.
+pub fn parse_meta(arg: &str) -> Result<(String, String)> {
+	let (key, value) = arg.split_once('=')
+						  .chain_err(|| format!("Invalid metadata \"{}\", expected \"<key>=<value>\".", arg))?;
+	let key = key.trim();
+	let key = key.strip_prefix("dc:").unwrap_or(key);
+	if key.is_empty() {
+		bail!("Invalid metadata \"{}\", the key is missing.", arg);
+	}
+	if key == "identifier" {
+		bail!("The identifier is set with \"--uuid\", rather than \"--meta\".");
+	}
+
+	let known = BUILDER_META.iter().any(|&(name, _)| name == key) || DUBLIN_CORE_META.contains(&key);
+	let prefixed = match key.split_once(':') {
+		Some((prefix, name)) => [prefix, name].iter().all(|part| {
+			part.starts_with(|c: char| c.is_ascii_alphabetic())
+				&& part.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.'))
+		}),
+		None => false,
+	};
+	if !known && !prefixed {
+		let names: Vec<&str> = BUILDER_META.iter()
+										   .map(|&(name, _)| name)
+										   .chain(DUBLIN_CORE_META.iter().cloned())
+										   .collect();
+		bail!("Unknown metadata key \"{}\": expected one of {}, or a prefixed name like \"calibre:rating\".", key, names.join(", "));
+	}
+	Ok((key.to_owned(), value.trim().to_owned()))
+}
+
+/// Assembles the fetched pages into an epub and writes it to `writer`, see `generate_epub`.
+pub fn build_epub<W, I>(info: &BookInfo, options: &EpubOptions, cover: Option<Image>, images: Option<&ImageStore>, pages: I, writer: W) -> Result<()>
+	where W: Write, I: IntoIterator<Item = Result<Page>> {
+	let epub = generate_epub(info, options, cover, images, pages)?;
+
+	let mut writer = writer;
+	writer.write_all(&epub)
+		  .chain_err(|| "Unable to write epub.")?;
+
+	Ok(())
+}
+
+/// Assembles the fetched pages into an epub, returning its bytes. It's built in memory throughout, without
+/// so much as a temporary file.
+///
+/// Pages are compressed into the epub as they arrive, while images are taken from `images` once every page has.
+/// The spine follows the order of `pages` rather than their indices, which `--no-sort` and merged books rely on,
+/// and which `OrderedPages` keeps to the order of the chapters whichever of them finishes fetching first.
+pub fn generate_epub<I>(info: &BookInfo, options: &EpubOptions, cover: Option<Image>, images: Option<&ImageStore>, pages: I) -> Result<Vec<u8>>
+	where I: IntoIterator<Item = Result<Page>> {
+	let zip = ZipLibrary::new()
+		.chain_err(|| "Unable to construct ZipLibrary.")?;
+	let mut builder: EpubBuilder<ZipLibrary> = EpubBuilder::new(zip)
+		.chain_err(|| "Unable to construct EpubBuilder")?;
+	// Metadata is inserted into the package document, the nav and the toc.ncx verbatim.
+	builder.metadata("title", escape_html(&info.title))
+		   .chain_err(|| "Unable to alter title.")?;
+	builder.metadata("toc_name", escape_html(&info.title))
+		   .chain_err(|| "Unable to alter Table of Contents.")?;
+	builder.metadata("author", escape_html(info.author.as_deref().unwrap_or("WuxiaWorld")))
+		   .chain_err(|| "Unable to set author metadata.")?;
+	builder.metadata("lang", options.lang.clone())
+		   .chain_err(|| "Unable to set language metadata.")?;
+	if let Some(ref description) = info.description {
+		builder.metadata("description", escape_html(description))
+			   .chain_err(|| "Unable to set description metadata.")?;
+	}
+	for tag in &info.tags {
+		builder.metadata("subject", escape_html(tag))
+			   .chain_err(|| "Unable to set subject metadata.")?;
+	}
+	// Set after the scraped metadata, so they replace it, while the rest are added to the package further down.
+	for (key, value) in &options.meta {
+		if let Some(&(_, name)) = BUILDER_META.iter().find(|&&(meta, _)| meta == key) {
+			// An empty value clears the lists (of authors, descriptions and subjects) instead of adding to them.
+			if matches!(name, "author" | "description" | "subject") {
+				builder.metadata(name, "")
+					   .chain_err(|| format!("Unable to set \"{}\" metadata.", key))?;
+			}
+			builder.metadata(name, escape_html(value))
+				   .chain_err(|| format!("Unable to set \"{}\" metadata.", key))?;
+		}
+	}
+
+	let stylesheet = options.stylesheet.as_deref().unwrap_or(DEFAULT_STYLESHEET);
+	builder.stylesheet(stylesheet.as_bytes())
+		   .chain_err(|| "Unable to add stylesheet.")?;
+
+	let cover_path = cover.as_ref().map(|cover| format!("cover.{}", image_extension(&cover.mime)));
+	if let (Some(cover), Some(path)) = (cover, cover_path.as_ref()) {
+		builder.add_cover_image(path, Cursor::new(cover.data), cover.mime)
+			   .chain_err(|| "Unable to add cover image.")?;
+	}
+
+	if options.title_page {
+		let content = EpubContent::new("title_page.xhtml", Cursor::new(finish_document(render_title_page(info, cover_path.as_deref()), options)))
+			.title("Title Page")
+			.reftype(ReferenceType::TitlePage);
+		builder.add_content(content)
+			   .chain_err(|| "Unable to add title page.")?;
+	}
+
+	if let Some(depth) = options.toc_page {
+		let content = EpubContent::new("contents.xhtml", Cursor::new(finish_document(render_toc_page(info, options, depth), options)))
+			.title("Contents")
+			.reftype(ReferenceType::Toc);
+		builder.add_content(content)
+			   .chain_err(|| "Unable to add contents page.")?;
+	}
+
+	let mut volume = None;
+	for page in pages {
+		let page = page?;
+		let name = format!("{}.xhtml", page.name);
+		let content = EpubContent::new(name.clone(), Cursor::new(finish_document(render_page_xhtml(&page, options.source_links), options)))
+			.reftype(ReferenceType::Text);
+		// The first chapter of every volume also stands in for the volume itself, nesting the rest beneath it.
+		// Titles go into the nav and the guide verbatim.
+		let title = escape_html(&page.title);
+		let content = match page.volume {
+			Some(ref volume_title) if volume.as_ref() != Some(volume_title) => {
+				content.title(escape_html(volume_title))
+					   .child(TocElement::new(name, title).level(2))
+			}
+			Some(_) => content.title(title).level(2),
+			None => content.title(title),
+		};
+		volume = page.volume;
+		builder.add_content(content)
+			   .chain_err(|| "Unable to add page.")?;
+	}
+
+	for (path, image) in images.map(ImageStore::take_images).unwrap_or_default() {
+		builder.add_resource(&path, Cursor::new(image.data), image.mime)
+			   .chain_err(|| format!("Unable to add image \"{}\".", path))?;
+	}
+
+	let mut epub = Cursor::new(Vec::new());
+	builder.generate(&mut epub)
+		   .chain_err(|| "Unable to generate epub")?;
+
+	// EpubBuilder picks a random identifier for every epub, which readers take to be a different book each time.
+	let identifier = options.identifier.clone().unwrap_or_else(|| book_identifier(Some(&info.url)));
+	rewrite_package(epub.get_ref(), options.compression, |package| {
+		let package = set_identifier(&package, &identifier)?;
+		let package = match options.series {
+			Some((ref name, index)) => add_series(&package, name, index)?,
+			None => package,
+		};
+		add_meta(&package, &options.meta)
+	})
+}
+
+/// A URN for the book fetched from `urls`, the same every time it's downloaded, so readers recognise an update
+/// as the book they already have.
+///
+/// Derived from each URL's host and path, so a trailing slash or switching between http and https doesn't give
+/// a new identifier.
+pub fn book_identifier<'a, I>(urls: I) -> String
+	where I: IntoIterator<Item = &'a Url> {
+	let canonical: Vec<String> = urls.into_iter()
+									 .map(|url| format!("{}{}", url.host_str().unwrap_or(""), url.path().trim_end_matches('/')))
+									 .collect();
+	Uuid::new_v5(&Uuid::NAMESPACE_URL, canonical.join(" ").as_bytes())
+		.urn()
+		.to_string()
+}
+
+/// Where `EpubBuilder` puts the package document.
+const PACKAGE_PATH: &str = "OEBPS/content.opf";
+/// Where `EpubBuilder` puts the toc.ncx.
+const NCX_PATH: &str = "OEBPS/toc.ncx";
+
+/// Rewrites the package document of `epub` with `rewrite`, for the metadata `EpubBuilder` has no way of
+/// setting, and unescapes the labels of its toc.ncx (see `unescape_nav_labels`), copying every other entry over
+/// as it is, or recompressed with `compression` if given.
+fn rewrite_package<F>(epub: &[u8], compression: Option<Compression>, rewrite: F) -> Result<Vec<u8>>
+	where F: FnOnce(String) -> Result<String> {
+	let options = compression.map_or_else(FileOptions::default, Compression::file_options);
+	let mut archive = ZipArchive::new(Cursor::new(epub))
+		.chain_err(|| "Unable to read generated epub.")?;
+	let mut writer = ZipWriter::new(Cursor::new(Vec::new()));
+	let mut rewrite = Some(rewrite);
+
+	for i in 0..archive.len() {
+		let mut file = archive.by_index(i)
+							  .chain_err(|| "Unable to read generated epub.")?;
+		match rewrite.take() {
+			Some(rewrite) if file.name() == PACKAGE_PATH => {
+				let mut package = String::new();
+				file.read_to_string(&mut package)
+					.chain_err(|| "Unable to read package document.")?;
+				writer.start_file(PACKAGE_PATH, options)
+					  .chain_err(|| "Unable to write package document.")?;
+				writer.write_all(rewrite(package)?.as_bytes())
+					  .chain_err(|| "Unable to write package document.")?;
+			}
+			other if file.name() == NCX_PATH => {
+				rewrite = other;
+				let mut ncx = String::new();
+				file.read_to_string(&mut ncx)
+					.chain_err(|| "Unable to read toc.ncx.")?;
+				writer.start_file(NCX_PATH, options)
+					  .chain_err(|| "Unable to write toc.ncx.")?;
+				writer.write_all(unescape_nav_labels(&ncx).as_bytes())
+					  .chain_err(|| "Unable to write toc.ncx.")?;
+			}
+			// The mimetype entry has to stay stored, so readers can tell what the file is from its first bytes.
+			other if compression.is_some() && file.name() != "mimetype" => {
+				rewrite = other;
+				let name = file.name().to_owned();
+				let mut contents = Vec::new();
+				file.read_to_end(&mut contents)
+					.chain_err(|| format!("Unable to read epub entry \"{}\".", name))?;
+				writer.start_file(name.as_str(), options)
+					  .chain_err(|| format!("Unable to write epub entry \"{}\".", name))?;
+				writer.write_all(&contents)
+					  .chain_err(|| format!("Unable to write epub entry \"{}\".", name))?;
+			}
+			other => {
+				rewrite = other;
+				// Copied without recompressing, which also keeps the mimetype entry stored.
+				writer.raw_copy_file(file)
+					  .chain_err(|| "Unable to copy epub entry.")?;
+			}
+		}
+	}
+
+	let epub = writer.finish()
+					 .chain_err(|| "Unable to finish epub.")?;
+	Ok(epub.into_inner())
+}
+
+/// Adds the `calibre:series` (and `calibre:series_index`) metadata to `package`.
+fn add_series(package: &str, name: &str, index: Option<f64>) -> Result<String> {
+	let mut meta = format!("    <meta name=\"calibre:series\" content=\"{}\"/>\n", escape_html(name));
+	if let Some(index) = index {
+		meta.push_str(&format!("    <meta name=\"calibre:series_index\" content=\"{}\"/>\n", index));
+	}
+	let end = package.find("  </metadata>")
+					 .chain_err(|| "No metadata in the package document.")?;
+	Ok(format!("{}{}{}", &package[..end], meta, &package[end..]))
+}
+
+/// Adds the `--meta` metadata `EpubBuilder` doesn't know of to `package`, replacing any it already has under the
+/// same key, such as the `dc:date` it sets itself.
+fn add_meta(package: &str, meta: &[(String, String)]) -> Result<String> {
+	let mut package = package.to_owned();
+	for (key, value) in meta {
+		if BUILDER_META.iter().any(|&(name, _)| name == key) {
+			continue;
+		}
+
+		let (element, opening) = if DUBLIN_CORE_META.contains(&key.as_str()) {
+			(format!("<dc:{key}>{}</dc:{key}>", escape_html(value), key = key), vec![format!("<dc:{}>", key), format!("<dc:{} ", key)])
+		} else {
+			(format!("<meta name=\"{}\" content=\"{}\"/>", escape_html(key), escape_html(value)), vec![format!("<meta name=\"{}\"", escape_html(key))])
+		};
+		package = package.lines()
+						 .filter(|line| !opening.iter().any(|opening| line.trim_start().starts_with(opening.as_str())))
+						 .map(|line| format!("{}\n", line))
+						 .collect();
+
+		let end = package.find("  </metadata>")
+						 .chain_err(|| "No metadata in the package document.")?;
+		package.insert_str(end, &format!("    {}\n", element));
+	}
+	Ok(package)
+}
+
+/// Undoes the escaping `EpubBuilder` gives the labels of the toc.ncx, which were handed to it escaped already
+/// as the nav and the guide use them verbatim. Only `&`, `<` and `>` are escaped by it, and each of them always,
+/// which is all that's undone.
+fn unescape_nav_labels(ncx: &str) -> String {
+	let mut in_label = false;
+	ncx.lines()
+	   .map(|line| {
+		   let trimmed = line.trim_start();
+		   let label = in_label && trimmed.starts_with("<text>");
+		   in_label = trimmed == "<navLabel>";
+		   match trimmed.strip_prefix("<text>").and_then(|text| text.strip_suffix("</text>")) {
+			   Some(text) if label => {
+				   let indent = &line[..line.len() - trimmed.len()];
+				   format!("{}<text>{}</text>\n", indent, unescape_text(text))
+			   }
+			   _ => format!("{}\n", line),
+		   }
+	   })
+	   .collect()
+}
+
+/// The inverse of `html_escape::encode_text`.
+fn unescape_text(text: &str) -> String {
+	let mut unescaped = String::with_capacity(text.len());
+	let mut rest = text;
+	while let Some(start) = rest.find('&') {
+		unescaped.push_str(&rest[..start]);
+		rest = &rest[start..];
+		let (c, length) = [("&amp;", '&'), ("&lt;", '<'), ("&gt;", '>')].iter()
+			.find(|(entity, _)| rest.starts_with(entity))
+			.map_or(('&', 1), |&(entity, c)| (c, entity.len()));
+		unescaped.push(c);
+		rest = &rest[length..];
+	}
+	unescaped.push_str(rest);
+	unescaped
+}
+
+/// Replaces the `dc:identifier` in `package` with `identifier`.
+fn set_identifier(package: &str, identifier: &str) -> Result<String> {
+	let open = "<dc:identifier id=\"epub-id-1\">";
+	let start = package.find(open)
+					   .chain_err(|| "No identifier in the package document.")? + open.len();
+	let end = package[start..].find("</dc:identifier>")
+							  .chain_err(|| "No identifier in the package document.")? + start;
+	Ok(format!("{}{}{}", &package[..start], escape_html(identifier), &package[end..]))
+}
+
+/// How many characters of the title fit on a line of the generated cover.
+const COVER_LINE_WIDTH: usize = 18;
+
+/// Draws a plain SVG cover holding the book's title and author, for books that don't come with one.
+pub fn generate_cover(info: &BookInfo) -> Image {
+	let mut lines: Vec<String> = Vec::new();
+	for word in info.title.split_whitespace() {
+		match lines.last_mut() {
+			Some(line) if line.chars().count() + 1 + word.chars().count() <= COVER_LINE_WIDTH => {
+				line.push(' ');
+				line.push_str(word);
+			}
+			_ => lines.push(word.to_owned()),
+		}
+	}
+
+	let top = 400 - lines.len() * 35;
+	let mut svg = String::from(r##"<?xml version="1.0" encoding="UTF-8"?>
+<svg xmlns="http://www.w3.org/2000/svg" width="600" height="800" viewBox="0 0 600 800">
+<rect width="600" height="800" fill="#2b2b3a"/>
+<rect x="30" y="30" width="540" height="740" fill="none" stroke="#c9a86a" stroke-width="4"/>
+"##);
+	for (i, line) in lines.iter().enumerate() {
+		svg.push_str(&format!(
+			"<text x=\"300\" y=\"{}\" font-family=\"serif\" font-size=\"48\" fill=\"#f0e6d2\" text-anchor=\"middle\">{}</text>\n",
+			top + i * 70, escape_html(line)));
+	}
+	if let Some(ref author) = info.author {
+		svg.push_str(&format!(
+			"<text x=\"300\" y=\"700\" font-family=\"serif\" font-size=\"28\" fill=\"#c9a86a\" text-anchor=\"middle\">{}</text>\n",
+			escape_html(author)));
+	}
+	svg.push_str("</svg>\n");
+
+	Image {
+		data: svg.into_bytes(),
+		mime: "image/svg+xml".to_owned(),
+	}
+}
+
+/// Wraps the chapter's paragraphs into a standalone XHTML document, followed by a link to the chapter's source
+/// if `source_link` is set.
+pub fn render_page_xhtml(page: &Page, source_link: bool) -> String {
+	let footer = if source_link { render_source_link(page) } else { String::new() };
+	render_document(&page.title, &format!("<h1>{}</h1>\n{}{}", escape_html(&page.title), page.content, footer))
+}
+
+/// Marks the start of the link to a chapter's source, which `read_existing_epub` leaves out of the content.
+pub const SOURCE_LINK_MARKER: &str = "<div class=\"source-link\">";
+
+fn render_source_link(page: &Page) -> String {
+	let link = escape_html(page.link.as_str());
+	format!("{}<a href=\"{}\">Source: {}</a></div>\n", SOURCE_LINK_MARKER, link, link)
+}
+
+/// Lists the book's details and synopsis along with where and when it was downloaded, under the cover when there is one.
+fn render_title_page(info: &BookInfo, cover_path: Option<&str>) -> String {
+	let mut body = String::from("<div class=\"title-page\">\n");
+	if let Some(path) = cover_path {
+		body.push_str(&format!("<img src=\"{}\" alt=\"Cover\"/>\n", escape_html(path)));
+	}
+	body.push_str(&format!("<h1>{}</h1>\n", escape_html(&info.title)));
+	if let Some(ref author) = info.author {
+		body.push_str(&format!("<p class=\"author\">{}</p>\n", escape_html(author)));
+	}
+	if let Some(ref description) = info.description {
+		body.push_str(&format!("<p class=\"description\">{}</p>\n", escape_html(description)));
+	}
+	let url = escape_html(info.url.as_str());
+	body.push_str(&format!("<p class=\"source\">Source: <a href=\"{}\">{}</a></p>\n", url, url));
+	body.push_str(&format!("<p class=\"generated\">Generated on {}</p>\n", Utc::now().format("%Y-%m-%d")));
+	body.push_str("</div>\n");
+
+	render_document(&info.title, &body)
+}
+
+/// Links to every chapter, grouped under their volumes when `depth` is at least 2.
+///
+/// The links have to match the names the pages are given once fetched, see `index_width`.
+fn render_toc_page(info: &BookInfo, options: &EpubOptions, depth: usize) -> String {
+	let width = index_width(&info.chapters);
+	let mut body = String::from("<h1>Contents</h1>\n<ol class=\"contents\">\n");
+	let mut volume = None;
+	for chapter in &info.chapters {
+		if depth >= 2 && chapter.volume != volume {
+			if volume.is_some() {
+				body.push_str("</ol>\n</li>\n");
+			}
+			if let Some(ref title) = chapter.volume {
+				body.push_str(&format!("<li>{}\n<ol>\n", escape_html(title)));
+			}
+			volume = chapter.volume.clone();
+		}
+		body.push_str(&format!("<li><a href=\"{}.xhtml\">{}</a></li>\n", chapter.padded_file_stem(width), escape_html(&chapter.page_title(&options.title_format))));
+	}
+	if volume.is_some() {
+		body.push_str("</ol>\n</li>\n");
+	}
+	body.push_str("</ol>\n");
+
+	render_document("Contents", &body)
+}
+
+fn finish_document(document: String, options: &EpubOptions) -> String {
+	if options.pretty_xhtml {
+		pretty_xhtml(&document)
+	} else {
+		document
+	}
+}
+
+/// Indents `document`, one of the XHTML documents rendered here, by how deeply each line's element is nested.
+///
+/// Those documents hold one element per line, with paragraphs never spanning several, so only the whitespace
+/// between elements changes and the text keeps exactly the whitespace it had.
+pub fn pretty_xhtml(document: &str) -> String {
+	let mut pretty = String::with_capacity(document.len() + document.len() / 4);
+	let mut depth: usize = 0;
+	for line in document.lines().map(str::trim).filter(|line| !line.is_empty()) {
+		if line.starts_with("</") {
+			depth = depth.saturating_sub(1);
+		}
+		pretty.push_str(&"  ".repeat(depth));
+		pretty.push_str(line);
+		pretty.push('\n');
+		if opens_element(line) {
+			depth += 1;
+		}
+	}
+	pretty
+}
+
+/// Whether `line` starts an element that's left open until a later line.
+fn opens_element(line: &str) -> bool {
+	if !line.starts_with('<') || line.starts_with("</") || line.starts_with("<?") || line.starts_with("<!") || line.ends_with("/>") {
+		return false;
+	}
+	let name: String = line[1..].chars()
+								.take_while(|c| c.is_ascii_alphanumeric())
+								.collect();
+	!name.is_empty() && !line.contains(&format!("</{}>", name))
+}
+
+fn render_document(title: &str, body: &str) -> String {
+	format!(r#"<?xml version="1.0" encoding="UTF-8"?>
+<html xmlns="http://www.w3.org/1999/xhtml">
+<head>
+<title>{title}</title>
+<link rel="stylesheet" type="text/css" href="stylesheet.css"/>
+</head>
+<body>
+{body}</body>
+</html>
+"#, title = escape_html(title), body = body)
+}
+
+/// Writes the pages as plain text, with a header line above every chapter and blank lines between paragraphs.
+pub fn write_text<W, I>(info: &BookInfo, pages: I, mut writer: W) -> Result<()>
+	where W: Write, I: IntoIterator<Item = Result<Page>> {
+	writeln!(writer, "{}\n", info.title)
+		.chain_err(|| "Unable to write text.")?;
+
+	for page in pages {
+		let page = page?;
+		writeln!(writer, "{}", render_page_text(&page))
+			.chain_err(|| format!("Unable to write \"{}\".", page.title))?;
+	}
+
+	writer.flush()
+		  .chain_err(|| "Unable to write text.")
+}
+
+/// Renders a chapter as plain text, underlining its title.
+pub fn render_page_text(page: &Page) -> String {
+	format!("{}\n{}\n\n{}\n", page.title, "=".repeat(page.title.chars().count()), render_text(&page.content))
+}
+
+/// Strips the markup from a chapter's content, separating paragraphs by blank lines.
+pub fn render_text(content: &str) -> String {
+	paragraphs(content).join("\n\n")
+}
+
+/// Writes the pages as a single Markdown document, with YAML front matter and a `##` heading per chapter.
+pub fn write_markdown<W, I>(info: &BookInfo, pages: I, mut writer: W) -> Result<()>
+	where W: Write, I: IntoIterator<Item = Result<Page>> {
+	writeln!(writer, "---")
+		.and_then(|_| writeln!(writer, "title: {}", yaml_string(&info.title)))
+		.and_then(|_| match info.author {
+			Some(ref author) => writeln!(writer, "author: {}", yaml_string(author)),
+			None => Ok(()),
+		})
+		.and_then(|_| writeln!(writer, "---\n"))
+		.chain_err(|| "Unable to write front matter.")?;
+
+	for page in pages {
+		let page = page?;
+		writeln!(writer, "{}", render_page_markdown(&page))
+			.chain_err(|| format!("Unable to write \"{}\".", page.title))?;
+	}
+
+	writer.flush()
+		  .chain_err(|| "Unable to write markdown.")
+}
+
+/// Renders a chapter as Markdown under a `##` heading.
+pub fn render_page_markdown(page: &Page) -> String {
+	format!("## {}\n\n{}\n", escape_markdown(&page.title), render_markdown(&page.content))
+}
+
+/// Writes the book's info as JSON, adding each page's XHTML content to its chapter as `content`.
+///
+/// Pages are matched to `info.chapters` by position, so an empty `pages` leaves the content out altogether.
+pub fn write_json<W, I>(info: &BookInfo, pages: I, mut writer: W) -> Result<()>
+	where W: Write, I: IntoIterator<Item = Result<Page>> {
+	let mut book = serde_json::to_value(info)
+		.chain_err(|| "Unable to serialize book info.")?;
+
+	if let Some(chapters) = book["chapters"].as_array_mut() {
+		for (chapter, page) in chapters.iter_mut().zip(pages) {
+			let page = page?;
+			chapter["words"] = page.words.into();
+			chapter["content"] = page.content.into();
+		}
+	}
+
+	serde_json::to_writer_pretty(&mut writer, &book)
+		.chain_err(|| "Unable to write JSON.")?;
+	writeln!(writer)
+		.and_then(|_| writer.flush())
+		.chain_err(|| "Unable to write JSON.")
+}
+
+/// Writes a manifest of the book's chapters as JSON, each with where it was fetched from and the length and
+/// SHA-256 of its content, so a later download can tell which chapters the site has since edited.
+pub fn write_manifest<W: Write>(info: &BookInfo, entries: &[ManifestEntry], mut writer: W) -> Result<()> {
+	let manifest = serde_json::json!({
+		"title": info.title,
+		"url": info.url.as_str(),
+		"generated": Utc::now().to_rfc3339(),
+		"chapters": entries,
+	});
+	serde_json::to_writer_pretty(&mut writer, &manifest)
+		.chain_err(|| "Unable to write manifest.")?;
+	writeln!(writer)
+		.and_then(|_| writer.flush())
+		.chain_err(|| "Unable to write manifest.")
+}
+
+/// Extra styles for the table of contents of the HTML export.
+const HTML_STYLESHEET: &str = "nav ol {
+	list-style: none;
+	padding: 0;
+}
+
+section {
+	margin-top: 3em;
+}
+";
+
+/// Writes the book as a single HTML document, with a table of contents linking to every chapter and the
+/// stylesheet (and language) from `options` embedded.
+pub fn write_html<W, I>(info: &BookInfo, options: &EpubOptions, pages: I, mut writer: W) -> Result<()>
+	where W: Write, I: IntoIterator<Item = Result<Page>> {
+	let stylesheet = options.stylesheet.as_deref().unwrap_or(DEFAULT_STYLESHEET);
+	let mut head = format!("<!DOCTYPE html>
+<html lang=\"{lang}\">
+<head>
+<meta charset=\"utf-8\"/>
+<title>{title}</title>
+<style>
+{stylesheet}{html_stylesheet}</style>
+</head>
+<body>
+<h1>{title}</h1>
+", lang = escape_html(&options.lang), title = escape_html(&info.title), stylesheet = stylesheet, html_stylesheet = HTML_STYLESHEET);
+	if let Some(ref author) = info.author {
+		head.push_str(&format!("<p class=\"author\">{}</p>\n", escape_html(author)));
+	}
+
+	// The anchors have to match the names the pages are given once fetched.
+	let width = index_width(&info.chapters);
+	head.push_str("<nav>\n<h2>Contents</h2>\n<ol>\n");
+	for chapter in &info.chapters {
+		head.push_str(&format!("<li><a href=\"#{}\">{}</a></li>\n", chapter.padded_file_stem(width), escape_html(&chapter.page_title(&options.title_format))));
+	}
+	head.push_str("</ol>\n</nav>\n");
+	writer.write_all(head.as_bytes())
+		  .chain_err(|| "Unable to write HTML.")?;
+
+	for page in pages {
+		let page = page?;
+		let footer = if options.source_links { render_source_link(&page) } else { String::new() };
+		write!(writer, "<section id=\"{}\">\n<h2>{}</h2>\n{}{}</section>\n", page.name, escape_html(&page.title), page.content, footer)
+			.chain_err(|| format!("Unable to write \"{}\".", page.title))?;
+	}
+
+	writer.write_all(b"</body>\n</html>\n")
+		  .and_then(|_| writer.flush())
+		  .chain_err(|| "Unable to write HTML.")
+}
+
+/// Renders a chapter as a standalone JSON object, holding its XHTML content.
+pub fn render_page_json(page: &Page) -> String {
+	let value = serde_json::json!({
+		"index": page.index,
+		"title": page.title,
+		"volume": page.volume,
+		"words": page.words,
+		"content": page.content,
+	});
+	format!("{:#}\n", value)
+}
+
+/// Writes every page to its own `<name>.<extension>` file inside of `dir`, rendered by `render`.
+/// Returns how many files were written.
+pub fn write_split<I, F>(pages: I, dir: &Path, extension: &str, render: F) -> Result<usize>
+	where I: IntoIterator<Item = Result<Page>>, F: Fn(&Page) -> String {
+	let mut written = 0;
+	for page in pages {
+		let page = page?;
+		let path = dir.join(format!("{}.{}", sanitize_filename(&page.name), extension));
+		write(&path, render(&page))
+			.chain_err(|| format!("Unable to write \"{}\".", path.display()))?;
+		written += 1;
+	}
+	Ok(written)
+}
+
+/// Converts a chapter's content into Markdown paragraphs.
+pub fn render_markdown(content: &str) -> String {
+	paragraphs(content).iter()
+					   .map(|paragraph| escape_markdown(paragraph))
+					   .collect::<Vec<_>>()
+					   .join("\n\n")
+}
+
+/// The text of every non-empty paragraph in a chapter's content, with scene breaks written as `* * *`.
+fn paragraphs(content: &str) -> Vec<String> {
+	let doc = Document::from(content);
+	doc.find(Name("p").or(Name("hr")))
+	   .map(|node| match node.name() {
+		   Some("hr") => "* * *".to_owned(),
+		   _ => node.text().trim().to_owned(),
+	   })
+	   .filter(|text| !text.is_empty())
+	   .collect()
+}
+
+/// Escapes inline emphasis/link characters, and anything at the start of a line that would turn it into a heading, quote or list.
+fn escape_markdown(text: &str) -> String {
+	let mut escaped = String::with_capacity(text.len());
+	for c in text.chars() {
+		if let '\\' | '`' | '*' | '_' | '[' | ']' = c {
+			escaped.push('\\');
+		}
+		escaped.push(c);
+	}
+
+	let digits = escaped.chars().take_while(char::is_ascii_digit).count();
+	if escaped.starts_with(['#', '>', '-', '+', '=']) {
+		escaped.insert(0, '\\');
+	} else if digits > 0 && escaped[digits..].starts_with(['.', ')']) {
+		escaped.insert(digits, '\\');
+	}
+	escaped
+}
+
+fn yaml_string(value: &str) -> String {
+	format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::validate_epub;
+	use std::env::temp_dir;
+	use std::fs::remove_file;
+	use std::process;
+
+	fn book(title: &str) -> BookInfo {
+		BookInfo {
+			title: title.to_owned(),
+			url: Url::parse("https://www.wuxiaworld.com/novel/foo").unwrap(),
+			author: Some("Tom & Jerry".to_owned()),
+			description: None,
+			tags: Vec::new(),
+			cover_url: None,
+			chapters: Vec::new(),
+		}
+	}
+
+	fn page(index: u32, title: &str) -> Page {
+		Page {
+			index,
+			name: format!("chapter_{}", index),
+			title: title.to_owned(),
+			volume: None,
+			content: "<p>Words here.</p>\n".to_owned(),
+			link: Url::parse(&format!("https://www.wuxiaworld.com/novel/foo/foo-chapter-{}", index)).unwrap(),
+			words: 2,
+			sha256: String::new(),
+		}
+	}
+
+	fn epub_options() -> EpubOptions {
+		EpubOptions {
+			title_page: true,
+			stylesheet: None,
+			lang: "en".to_owned(),
+			source_links: false,
+			title_format: "Chapter {index}: {title}".to_owned(),
+			identifier: None,
+			series: Some(("Asura & Co".to_owned(), None)),
+			toc_page: Some(1),
+			pretty_xhtml: false,
+			meta: vec![("publisher".to_owned(), "X & Y".to_owned())],
+			compression: None,
+		}
+	}
+
+	/// Runs `--validate` over an epub built from `info` and `pages`.
+	fn validate(info: &BookInfo, pages: Vec<Page>) -> Result<usize> {
+		let epub = generate_epub(info, &epub_options(), None, None, pages.into_iter().map(Ok))?;
+		let path = temp_dir().join(format!("wuxia-dl-test-{}-{}.epub", process::id(), info.title.len()));
+		write(&path, epub).unwrap();
+		let result = validate_epub(&path);
+		remove_file(&path).unwrap();
+		result
+	}
+
+	#[test]
+	fn chapter_titles_with_markup_characters_validate() {
+		validate(&book("Foo"), vec![page(1, "Chapter 1: Start & End <3"), page(2, "Chapter 2: \"Quoted\"")]).unwrap();
+	}
+
+	#[test]
+	fn book_titles_with_markup_characters_validate() {
+		validate(&book("Martial God & Asura <Remastered>"), vec![page(1, "Chapter 1: Start")]).unwrap();
+	}
+
+	#[test]
+	fn titles_that_look_like_entities_validate() {
+		validate(&book("Foo"), vec![page(1, "Chapter 1: R&D; Arc"), page(2, "Chapter 2: &nbsp;"), page(3, "Chapter 3: &amp;")]).unwrap();
+	}
+
+	#[test]
+	fn titles_are_escaped_once_everywhere() {
+		let titles = ["Chapter 1: R&D; Arc", "Chapter 2: &nbsp;", "Chapter 3: &amp; <3"];
+		let pages = titles.iter().enumerate().map(|(i, title)| Ok(page(i as u32 + 1, title)));
+		let epub = generate_epub(&book("Foo"), &epub_options(), None, None, pages).unwrap();
+		let mut archive = ZipArchive::new(Cursor::new(epub)).unwrap();
+		for name in ["OEBPS/nav.xhtml", "OEBPS/toc.ncx", PACKAGE_PATH] {
+			let mut contents = String::new();
+			archive.by_name(name).unwrap().read_to_string(&mut contents).unwrap();
+			for title in titles {
+				assert!(contents.contains(&escape_html(title)), "{} is missing {:?}:\n{}", name, escape_html(title), contents);
+			}
+		}
+	}
+}
@@ -0,0 +1,232 @@
+use select::node::Node;
+use select::predicate::Predicate;
+use super::errors::*;
+
+/// A CSS-like selector parsed at runtime, supporting tags, `.class`, `#id`, `[attr]` and `[attr=value]`,
+/// combined with descendant (` `) and child (`>`) combinators.
+#[derive(Debug, Clone)]
+pub struct Selector {
+	/// The compounds from left to right, each paired with how it relates to the compound before it.
+	steps: Vec<(Combinator, Compound)>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Combinator {
+	Descendant,
+	Child,
+}
+
+#[derive(Debug, Clone, Default)]
+struct Compound {
+	tag: Option<String>,
+	classes: Vec<String>,
+	id: Option<String>,
+	attrs: Vec<(String, Option<String>)>,
+}
+
+impl Selector {
+	pub fn parse(selector: &str) -> Result<Selector> {
+		let mut steps = Vec::new();
+		let mut combinator = Combinator::Descendant;
+		let mut rest = selector.trim();
+		if rest.is_empty() {
+			bail!("Empty selector");
+		}
+
+		while !rest.is_empty() {
+			if let Some(after) = rest.strip_prefix('>') {
+				if steps.is_empty() || combinator == Combinator::Child {
+					bail!("Invalid selector \"{}\": misplaced \">\"", selector);
+				}
+				combinator = Combinator::Child;
+				rest = after.trim_start();
+				continue;
+			}
+
+			let end = compound_end(rest);
+			if end == 0 {
+				bail!("Invalid selector \"{}\": unexpected \"{}\"", selector, rest);
+			}
+			let compound = Compound::parse(&rest[..end])
+				.chain_err(|| format!("Invalid selector \"{}\"", selector))?;
+			steps.push((combinator, compound));
+
+			combinator = Combinator::Descendant;
+			rest = rest[end..].trim_start();
+		}
+
+		if combinator == Combinator::Child {
+			bail!("Invalid selector \"{}\": nothing follows \">\"", selector);
+		}
+		Ok(Selector {
+			steps,
+		})
+	}
+
+	fn matches_step(&self, step: usize, node: &Node) -> bool {
+		let (combinator, ref compound) = self.steps[step];
+		if !compound.matches(node) {
+			return false;
+		}
+		if step == 0 {
+			return true;
+		}
+
+		match combinator {
+			Combinator::Child => node.parent().is_some_and(|parent| self.matches_step(step - 1, &parent)),
+			Combinator::Descendant => {
+				std::iter::successors(node.parent(), |n| n.parent()).any(|ancestor| self.matches_step(step - 1, &ancestor))
+			}
+		}
+	}
+}
+
+impl Predicate for &Selector {
+	fn matches(&self, node: &Node) -> bool {
+		self.matches_step(self.steps.len() - 1, node)
+	}
+}
+
+/// The length of the compound at the start of `selector`, which runs until a combinator or whitespace
+/// outside of an attribute's brackets.
+fn compound_end(selector: &str) -> usize {
+	let mut in_brackets = false;
+	for (i, c) in selector.char_indices() {
+		match c {
+			'[' => in_brackets = true,
+			']' => in_brackets = false,
+			'>' if !in_brackets => return i,
+			c if c.is_whitespace() && !in_brackets => return i,
+			_ => {}
+		}
+	}
+	selector.len()
+}
+
+impl Compound {
+	fn parse(compound: &str) -> Result<Compound> {
+		let mut parsed = Compound::default();
+
+		let tag_end = compound.find(['.', '#', '[']).unwrap_or(compound.len());
+		match &compound[..tag_end] {
+			"" | "*" => {}
+			tag if tag.chars().all(is_name_char) => parsed.tag = Some(tag.to_lowercase()),
+			tag => bail!("Invalid tag \"{}\"", tag),
+		}
+
+		let mut rest = &compound[tag_end..];
+		while let Some(marker) = rest.chars().next() {
+			rest = &rest[1..];
+			if marker == '[' {
+				let end = rest.find(']')
+							  .chain_err(|| "Unclosed \"[\"")?;
+				let attr = &rest[..end];
+				rest = &rest[end + 1..];
+
+				let (name, value) = match attr.find('=') {
+					Some(i) => (&attr[..i], Some(attr[i + 1..].trim().trim_matches(['"', '\'']).to_owned())),
+					None => (attr, None),
+				};
+				let name = name.trim();
+				if name.is_empty() || !name.chars().all(is_name_char) {
+					bail!("Invalid attribute \"{}\"", attr);
+				}
+				parsed.attrs.push((name.to_owned(), value));
+				continue;
+			}
+
+			let end = rest.find(['.', '#', '[']).unwrap_or(rest.len());
+			let name = &rest[..end];
+			rest = &rest[end..];
+			if name.is_empty() || !name.chars().all(is_name_char) {
+				bail!("Invalid name \"{}\" after \"{}\"", name, marker);
+			}
+			match marker {
+				'.' => parsed.classes.push(name.to_owned()),
+				'#' => parsed.id = Some(name.to_owned()),
+				_ => bail!("Unexpected \"{}\"", marker),
+			}
+		}
+
+		Ok(parsed)
+	}
+
+	fn matches(&self, node: &Node) -> bool {
+		if let Some(ref tag) = self.tag {
+			if node.name() != Some(tag.as_str()) {
+				return false;
+			}
+		} else if node.name().is_none() {
+			return false;
+		}
+
+		let classes = node.attr("class").unwrap_or_default();
+		self.classes.iter().all(|class| classes.split_whitespace().any(|c| c == class))
+			&& self.id.as_ref().is_none_or(|id| node.attr("id") == Some(id.as_str()))
+			&& self.attrs.iter().all(|(name, value)| match value {
+				Some(value) => node.attr(name) == Some(value.as_str()),
+				None => node.attr(name).is_some(),
+			})
+	}
+}
+
+fn is_name_char(c: char) -> bool {
+	c.is_alphanumeric() || c == '-' || c == '_'
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use select::document::Document;
+
+	const PAGE: &str = r#"<div id="page" class="content main">
+<p id="intro" class="lead">Intro</p>
+<section id="chapter" data-kind="chapter"><div id="text" class="fr-view"><p id="first">First</p><span><p id="nested">Nested</p></span></div></section>
+<a id="next" href="/chapter-2" rel="next">Next</a>
+</div>"#;
+
+	/// The ids of the elements in `PAGE` that `selector` matches, in document order.
+	fn ids(selector: &str) -> Vec<String> {
+		let selector = Selector::parse(selector).unwrap();
+		Document::from(PAGE).find(&selector)
+							.filter_map(|node| node.attr("id").map(str::to_owned))
+							.collect()
+	}
+
+	#[test]
+	fn tags_classes_and_ids_are_matched() {
+		assert_eq!(ids("section"), ["chapter"]);
+		assert_eq!(ids("SECTION"), ["chapter"]);
+		assert_eq!(ids(".lead"), ["intro"]);
+		assert_eq!(ids("div.content.main"), ["page"]);
+		assert_eq!(ids("div.content.missing"), Vec::<String>::new());
+		assert_eq!(ids("#first"), ["first"]);
+		assert_eq!(ids("p#nested"), ["nested"]);
+		assert_eq!(ids("*.fr-view"), ["text"]);
+	}
+
+	#[test]
+	fn attributes_are_matched_with_or_without_a_value() {
+		assert_eq!(ids("[href]"), ["next"]);
+		assert_eq!(ids("a[rel=next]"), ["next"]);
+		assert_eq!(ids(r#"a[rel="next"]"#), ["next"]);
+		assert_eq!(ids("[data-kind='chapter']"), ["chapter"]);
+		assert_eq!(ids("a[rel=prev]"), Vec::<String>::new());
+	}
+
+	#[test]
+	fn descendants_and_children_are_told_apart() {
+		assert_eq!(ids(".fr-view p"), ["first", "nested"]);
+		assert_eq!(ids(".fr-view > p"), ["first"]);
+		assert_eq!(ids(".fr-view>p"), ["first"]);
+		assert_eq!(ids("#page section > div span p"), ["nested"]);
+		assert_eq!(ids("section > p"), Vec::<String>::new());
+	}
+
+	#[test]
+	fn malformed_selectors_are_errors() {
+		for selector in ["", "  ", ">", "> p", "div >", "div > > p", "[", "div[href", "[=next]", "[two words]", ".", "#", "p.", "di$v", "a]", "div, p"] {
+			assert!(Selector::parse(selector).is_err(), "{:?} parsed", selector);
+		}
+	}
+}
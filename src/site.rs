@@ -0,0 +1,721 @@
+use regex::Regex;
+use select::document::Document;
+use select::node::Node;
+use select::predicate::{Attr, Class, Name, Predicate};
+use super::errors::*;
+use super::config::SelectorConfig;
+use super::selector::Selector;
+use super::{BookInfo, Chapter, escape_html};
+use std::collections::HashSet;
+use std::sync::OnceLock;
+use url::Url;
+
+/// Knows how to pull the book info and chapter text out of a particular site's markup.
+pub trait SiteAdapter: Sync {
+	/// The hosts this adapter understands.
+	fn hosts(&self) -> &'static [&'static str];
+
+	/// Parses the book's metadata along with the chapters listed on this page.
+	fn parse_book_info(&self, doc: &Document, url: &Url) -> Result<BookInfo>;
+
+	/// Parses the chapters listed on a table of contents page. Chapters whose title carries no index are
+	/// returned with `numbered` unset, to be given one by `synthesize_indices` once every page is known.
+	fn parse_chapter_list(&self, doc: &Document, url: &Url) -> Result<Vec<Chapter>>;
+
+	/// Links to further table of contents pages, for books whose chapter list is paginated.
+	fn toc_pages(&self, _doc: &Document, _url: &Url) -> Vec<Url> {
+		Vec::new()
+	}
+
+	/// Extracts the chapter text, returning an empty string if nothing could be found.
+	///
+	/// Bold and italic text is kept as `<strong>` and `<em>`, and underlined text as `<span class="underline">`.
+	/// Scene breaks are emitted as `<hr class="scene-break"/>`.
+	///
+	/// Images are emitted as `<div class="image"><img src="..." alt=""/></div>` with an absolute `src` resolved
+	/// against `url`, so they can be downloaded and rewritten once the chapter is fetched.
+	fn parse_chapter(&self, doc: &Document, url: &Url) -> Result<String>;
+
+	/// Whether `doc` has the structure of a chapter page, rather than, say, an error page that happens to
+	/// have a paragraph or two in it.
+	fn is_chapter_page(&self, _doc: &Document) -> bool {
+		true
+	}
+
+	/// The page the chapter whose first page is at `chapter` continues on after `doc` (served from `url`), for
+	/// chapters split across several pages.
+	fn next_chapter_page(&self, _doc: &Document, _url: &Url, _chapter: &Url) -> Option<Url> {
+		None
+	}
+
+	/// The book a chapter page belongs to, for when a chapter is given in place of its book.
+	fn book_page(&self, _doc: &Document, _url: &Url) -> Option<Url> {
+		None
+	}
+}
+
+static ADAPTERS: &[&dyn SiteAdapter] = &[&WuxiaWorldAdapter];
+
+/// Selects the adapter responsible for the host of `url`.
+pub fn adapter_for(url: &Url) -> Result<&'static dyn SiteAdapter> {
+	let host = url.host_str()
+				  .chain_err(|| format!("No host in url: \"{}\"", url))?;
+
+	match ADAPTERS.iter().find(|adapter| adapter.hosts().contains(&host)) {
+		Some(adapter) => Ok(*adapter),
+		None => bail!(ErrorKind::Unsupported(host.to_owned())),
+	}
+}
+
+pub fn supported_hosts() -> Vec<&'static str> {
+	ADAPTERS.iter()
+			.flat_map(|adapter| adapter.hosts().iter().cloned())
+			.collect()
+}
+
+macro_rules! try_with {
+    ($content:ident, $doc:ident, $url:ident, $target:expr) => (
+		for node in $doc.find($target).filter(|node| !is_hidden(node)) {
+			push_paragraph(&mut $content, &inline_html(&node));
+			push_images(&mut $content, &node, $url);
+		}
+    )
+}
+
+/// Whether a link reading `text` leads from a chapter back to its book's table of contents.
+fn is_contents_label(text: &str) -> bool {
+	let label = collapse_whitespace(text).to_lowercase();
+	matches!(label.as_str(), "table of contents" | "contents" | "index" | "toc" | "chapter list" | "chapters")
+}
+
+/// How a link reading `text` reads as leading on to the next page: `Some(true)` for "Next Page" (or "Next Page »"),
+/// which only ever does, and `Some(false)` for a bare "Next", which the next chapter's link reads as too.
+fn next_page_label(text: &str) -> Option<bool> {
+	let label = collapse_whitespace(text).to_lowercase();
+	match label.trim_end_matches(['»', '›', '>', '→']).trim_end() {
+		"next page" => Some(true),
+		"next" => Some(false),
+		_ => None,
+	}
+}
+
+/// Whether `next` is another page of the chapter at `chapter`, the same path with another query (`?page=2`) or a
+/// path beneath it (`/chapter-1/2`), rather than another chapter.
+fn continues_chapter(next: &Url, chapter: &Url) -> bool {
+	let path = chapter.path().trim_end_matches('/');
+	next.host_str() == chapter.host_str()
+		&& (next.path().trim_end_matches('/') == path || next.path().starts_with(&format!("{}/", path)))
+}
+
+/// The URL the hrefs on `doc` are relative to: the one it was served from (after any redirects), unless it
+/// names another with `<base href>`.
+fn base_url(doc: &Document, url: &Url) -> Url {
+	doc.find(Name("base"))
+	   .filter_map(|node| node.attr("href"))
+	   .find_map(|href| resolve_href(url, href).ok())
+	   .unwrap_or_else(|| url.clone())
+}
+
+/// Resolves `href` against `base`, with each shape it comes in handled explicitly.
+///
+/// Protocol-relative hrefs (`//host/path`) keep the scheme of `base` but replace everything else, root-relative
+/// ones (`/path`) keep its host but replace its path, and the rest are either absolute or relative to the
+/// directory of `base`'s path. The whitespace some pages leave around their hrefs is ignored.
+pub(crate) fn resolve_href(base: &Url, href: &str) -> Result<Url> {
+	let href = href.trim();
+	let resolved = if href.starts_with("//") {
+		Url::parse(&format!("{}:{}", base.scheme(), href))
+	} else if href.starts_with('/') {
+		let mut root = base.clone();
+		root.set_path("/");
+		root.set_query(None);
+		root.set_fragment(None);
+		root.join(href)
+	} else {
+		base.join(href)
+	};
+	resolved.chain_err(|| format!("Unable to append href (\"{}\") to url (\"{}\").", href, base))
+}
+
+fn push_images(content: &mut String, node: &Node, url: &Url) {
+	for img in node.find(Name("img")) {
+		if let Some(src) = img.attr("src").and_then(|src| resolve_href(url, src).ok()) {
+			content.push_str(&format!("<div class=\"image\"><img src=\"{}\" alt=\"\"/></div>\n", escape_html(src.as_str())));
+		}
+	}
+}
+
+/// Stands in for a blank paragraph until `finish_scene_breaks` decides what it was for.
+const BLANK_PARAGRAPH: &str = "<p></p>\n";
+/// What scene break markers (and blank paragraphs separating scenes) are turned into.
+const SCENE_BREAK: &str = "<hr class=\"scene-break\"/>\n";
+/// Blank paragraphs more frequent than one per this many paragraphs are spacing, see `finish_scene_breaks`.
+const MAX_PARAGRAPHS_PER_SCENE_BREAK: usize = 4;
+
+/// Appends the already escaped `html` as a `<p>` element, with its runs of whitespace (non-breaking spaces,
+/// tabs and newlines included) collapsed into single spaces.
+fn push_paragraph(content: &mut String, html: &str) {
+	let html = collapse_whitespace(html);
+	if html.is_empty() {
+		content.push_str(BLANK_PARAGRAPH);
+		return;
+	}
+	if is_scene_break_marker(&html) {
+		content.push_str(SCENE_BREAK);
+		return;
+	}
+	content.push_str("<p>");
+	content.push_str(&html);
+	content.push_str("</p>\n");
+}
+
+/// Whether the paragraph is just one of the configured scene break markers, such as `***` or `◇◇◇`.
+fn is_scene_break_marker(html: &str) -> bool {
+	let mut text = String::new();
+	let mut in_tag = false;
+	for c in html.chars() {
+		match c {
+			'<' => in_tag = true,
+			'>' => in_tag = false,
+			c if !in_tag && !c.is_whitespace() => text.push(c),
+			_ => {}
+		}
+	}
+	// Markers are only a few characters long, so anything longer needn't be looked up.
+	text.chars().count() <= MAX_MARKER_CHARS && selectors().scene_break_markers.contains(&text)
+}
+
+/// The longest a scene break marker can be, see `is_scene_break_marker`.
+const MAX_MARKER_CHARS: usize = 16;
+
+/// Most blank paragraphs only space out the text, and are dropped, but the odd one between paragraphs marks a
+/// scene break, which is kept as a rule.
+fn finish_scene_breaks(content: &str) -> String {
+	// Only runs of blank paragraphs between two others could be breaks, with a run counting as a single one.
+	let paragraphs: Vec<(bool, &str)> = content.split_inclusive('\n')
+												.map(|line| (line == BLANK_PARAGRAPH, line))
+												.collect();
+	let text = paragraphs.iter().filter(|(blank, _)| !blank).count();
+	let breaks = paragraphs.iter()
+						   .skip_while(|(blank, _)| *blank)
+						   .collect::<Vec<_>>()
+						   .windows(2)
+						   .filter(|pair| !pair[0].0 && pair[1].0)
+						   .count();
+	let trailing = paragraphs.last().is_some_and(|(blank, _)| *blank);
+	let breaks = breaks.saturating_sub(usize::from(trailing));
+	let keep = breaks * MAX_PARAGRAPHS_PER_SCENE_BREAK <= text;
+
+	let mut finished = String::with_capacity(content.len());
+	let mut pending = false;
+	for (blank, line) in paragraphs {
+		if blank {
+			// Leading breaks are dropped and trailing ones never get flushed.
+			pending = keep && !finished.is_empty();
+			continue;
+		}
+		// A marker next to blank paragraphs, or straight after another one, still makes for a single break, and
+		// none is needed before the first paragraph.
+		if line == SCENE_BREAK && (finished.is_empty() || finished.ends_with(SCENE_BREAK)) {
+			continue;
+		}
+		if pending && line != SCENE_BREAK && !finished.ends_with(SCENE_BREAK) {
+			finished.push_str(SCENE_BREAK);
+		}
+		pending = false;
+		finished.push_str(line);
+	}
+	finished
+}
+
+/// The escaped text of `node`, keeping its bold, italic and underlined runs (see `SiteAdapter::parse_chapter`)
+/// while flattening any other markup.
+fn inline_html(node: &Node) -> String {
+	if let Some(text) = node.as_text() {
+		return escape_html(text);
+	}
+	if is_hidden(node) {
+		return String::new();
+	}
+	if node.name() == Some("br") {
+		return " ".to_owned();
+	}
+
+	let inner: String = node.children()
+							.map(|child| inline_html(&child))
+							.collect();
+	let (open, close) = match node.name() {
+		Some("strong") | Some("b") => ("<strong>", "</strong>"),
+		Some("em") | Some("i") => ("<em>", "</em>"),
+		Some("u") => ("<span class=\"underline\">", "</span>"),
+		_ => return inner,
+	};
+	if inner.trim().is_empty() {
+		inner
+	} else {
+		format!("{}{}{}", open, inner, close)
+	}
+}
+
+/// Whether `node` is never shown to readers, like the ad loaders some content containers have scripts for,
+/// and so has no place in the chapter.
+fn is_hidden(node: &Node) -> bool {
+	node.as_comment().is_some() || matches!(node.name(), Some("script") | Some("style") | Some("noscript"))
+}
+
+/// Joins runs of sibling spans into a single paragraph, as some chapters style every sentence separately.
+fn push_span_paragraphs(content: &mut String, doc: &Document) {
+	let mut run: Option<(Option<usize>, String)> = None;
+	for node in doc.find(Class("fr-view").descendant(Name("span"))) {
+		let parent = node.parent().map(|parent| parent.index());
+		match run {
+			Some((current, ref mut text)) if current == parent => text.push_str(&inline_html(&node)),
+			_ => {
+				if let Some((_, text)) = run.take() {
+					push_paragraph(content, &text);
+				}
+				run = Some((parent, inline_html(&node)));
+			}
+		}
+	}
+	if let Some((_, text)) = run {
+		push_paragraph(content, &text);
+	}
+}
+
+/// Joins every run of whitespace (including newlines between paragraphs) into a single space.
+fn collapse_whitespace(text: &str) -> String {
+	text.split_whitespace()
+		.collect::<Vec<_>>()
+		.join(" ")
+}
+
+/// Tidies up the volumes the chapters were grouped into.
+///
+/// Volumes that restart their chapter numbers are shifted past the ones before them, so indices stay unique
+/// across the book, and a book with only a single volume is treated as having none.
+pub fn normalize_volumes(chapters: &mut [Chapter]) {
+	let volumes: HashSet<&String> = chapters.iter().filter_map(|chapter| chapter.volume.as_ref()).collect();
+	if volumes.len() < 2 {
+		for chapter in chapters.iter_mut() {
+			chapter.volume = None;
+		}
+		return;
+	}
+
+	let mut seen: HashSet<u32> = HashSet::new();
+	let mut start = 0;
+	while start < chapters.len() {
+		let volume = chapters[start].volume.clone();
+		let end = chapters[start..].iter()
+								   .position(|chapter| chapter.volume != volume)
+								   .map_or(chapters.len(), |len| start + len);
+		let run = &mut chapters[start..end];
+
+		let restarted = volume.is_some() && run.iter().any(|chapter| chapter.numbered && seen.contains(&chapter.index));
+		if restarted {
+			let offset = seen.iter().max().copied().unwrap_or(0);
+			for chapter in run.iter_mut().filter(|chapter| chapter.numbered) {
+				chapter.index += offset;
+			}
+		}
+		seen.extend(run.iter().filter(|chapter| chapter.numbered).map(|chapter| chapter.index));
+
+		start = end;
+	}
+}
+
+/// Gives the chapters whose title carried no index an index of their own.
+///
+/// Prologues and the like come before chapter 1 as index 0, while everything else (epilogues, interludes,
+/// side stories) shares the index of the numbered chapter listed before it, so sorting by index keeps it where
+/// it was listed. `check_duplicates` then tells their files apart.
+pub fn synthesize_indices(chapters: &mut [Chapter]) {
+	let mut previous = 0;
+	for chapter in chapters.iter_mut() {
+		if chapter.numbered {
+			previous = chapter.index;
+			continue;
+		}
+		let title = chapter.title.to_lowercase();
+		if ["prologue", "prelude", "preface", "foreword"].iter().any(|word| title.contains(word)) {
+			chapter.index = 0;
+		} else {
+			chapter.index = previous;
+		}
+	}
+}
+
+/// The compiled form of a `SelectorConfig`.
+struct Selectors {
+	book_title: Selector,
+	cover: Selector,
+	author_labels: Selector,
+	tags: Selector,
+	chapter_links: Selector,
+	chapter_dates: Selector,
+	content: Vec<Selector>,
+	next_page: Selector,
+	/// With their whitespace removed.
+	scene_break_markers: Vec<String>,
+}
+
+impl Selectors {
+	fn compile(config: &SelectorConfig) -> Result<Selectors> {
+		Ok(Selectors {
+			book_title: Selector::parse(&config.book_title)?,
+			cover: Selector::parse(&config.cover)?,
+			author_labels: Selector::parse(&config.author_labels)?,
+			tags: Selector::parse(&config.tags)?,
+			chapter_links: Selector::parse(&config.chapter_links)?,
+			chapter_dates: Selector::parse(&config.chapter_dates)?,
+			content: config.content
+						   .iter()
+						   .map(|selector| Selector::parse(selector))
+						   .collect::<Result<_>>()?,
+			next_page: Selector::parse(&config.next_page)?,
+			scene_break_markers: config.scene_break_markers
+									   .iter()
+									   .map(|marker| marker.chars().filter(|c| !c.is_whitespace()).collect())
+									   .filter(|marker: &String| !marker.is_empty())
+									   .collect(),
+		})
+	}
+}
+
+static SELECTORS: OnceLock<Selectors> = OnceLock::new();
+
+/// Replaces the built-in selectors, which has to happen before the first page is parsed.
+pub fn configure_selectors(config: &SelectorConfig) -> Result<()> {
+	let selectors = Selectors::compile(config)
+		.chain_err(|| "Invalid selector configuration.")?;
+	SELECTORS.set(selectors)
+			 .map_err(|_| "Selectors have already been configured.".into())
+}
+
+fn selectors() -> &'static Selectors {
+	SELECTORS.get_or_init(|| Selectors::compile(&SelectorConfig::default()).expect("The default selectors are valid"))
+}
+
+pub struct WuxiaWorldAdapter;
+
+impl SiteAdapter for WuxiaWorldAdapter {
+	fn hosts(&self) -> &'static [&'static str] {
+		&["www.wuxiaworld.com", "wuxiaworld.com"]
+	}
+
+	fn parse_book_info(&self, doc: &Document, url: &Url) -> Result<BookInfo> {
+		let selectors = selectors();
+		let base = base_url(doc, url);
+		let book_title = doc.find(&selectors.book_title).next()
+							.chain_err(|| ErrorKind::ParseError("Failed to locate book title".to_owned()))?
+			.text();
+
+		let cover_url = doc.find(&selectors.cover)
+						   .filter_map(|node| node.attr("src"))
+						   .find_map(|src| resolve_href(&base, src).ok());
+
+		let mut authors: Vec<String> = Vec::new();
+		for node in doc.find(&selectors.author_labels) {
+			let label = node.text().trim().to_lowercase();
+			if !label.starts_with("author") && !label.starts_with("translator") {
+				continue;
+			}
+
+			let value = std::iter::successors(node.next(), |n| n.next())
+				.find(|n| n.name().is_some())
+				.filter(|n| n.name() == Some("dd"));
+			if let Some(value) = value {
+				for name in value.text().split(',') {
+					let name = name.trim();
+					if !name.is_empty() && !authors.iter().any(|a| a == name) {
+						authors.push(name.to_owned());
+					}
+				}
+			}
+		}
+		let author = if authors.is_empty() {
+			None
+		} else {
+			Some(authors.join(", "))
+		};
+
+		let mut tags: Vec<String> = Vec::new();
+		for node in doc.find(&selectors.tags) {
+			let tag = collapse_whitespace(&node.text());
+			if !tag.is_empty() && !tags.iter().any(|t| t.eq_ignore_ascii_case(&tag)) {
+				tags.push(tag);
+			}
+		}
+
+		// The synopsis follows its heading, with the page's meta description as a shorter fallback.
+		let description = doc.find(Name("h3"))
+							 .find(|node| node.text().trim().eq_ignore_ascii_case("synopsis"))
+							 .and_then(|heading| std::iter::successors(heading.next(), |n| n.next()).find(|n| n.name().is_some()))
+							 .map(|node| node.text())
+							 .or_else(|| {
+								 doc.find(Name("meta").and(Attr("property", "og:description")))
+									.find_map(|node| node.attr("content"))
+									.map(str::to_owned)
+							 })
+							 .map(|text| collapse_whitespace(&text))
+							 .filter(|text| !text.is_empty());
+
+		Ok(BookInfo {
+			title: book_title,
+			url: url.clone(),
+			author,
+			description,
+			tags,
+			cover_url,
+			chapters: self.parse_chapter_list(doc, url)?,
+		})
+	}
+
+	fn parse_chapter_list(&self, doc: &Document, url: &Url) -> Result<Vec<Chapter>> {
+		let chapter_regex = Regex::new(r".+?(\d+)[- ]*(.*)")
+			.chain_err(|| "Unable to construct regex.")?;
+
+		let selectors = selectors();
+		let base = base_url(doc, url);
+		let mut chapters = Vec::new();
+		for node in doc.find(&selectors.chapter_links) {
+			let full_title = node.text().trim().to_owned();
+
+			let (index, title, numbered) = match chapter_regex.captures(&full_title) {
+				Some(cap) => {
+					let raw_index = &cap[1];
+					let index = raw_index.parse::<u32>()
+										 .chain_err(|| ErrorKind::ParseError(format!("Unable to parse index {}", raw_index)))?;
+					(index, cap[2].to_owned(), true)
+				}
+				None => (0, full_title, false),
+			};
+
+			let href = node.attr("href")
+						   .chain_err(|| ErrorKind::ParseError("No href specified".to_owned()))?;
+			let link = resolve_href(&base, href)?;
+
+			// Books split into volumes list each one's chapters in its own collapsible panel.
+			let volume = std::iter::successors(node.parent(), |n| n.parent())
+				.find(|n| n.is(Class("panel")))
+				.and_then(|panel| {
+					panel.find(Class("panel-title").descendant(Class("title")))
+						 .chain(panel.find(Class("panel-title")))
+						 .next()
+				})
+				.map(|heading| collapse_whitespace(&heading.text()))
+				.filter(|heading| !heading.is_empty());
+
+			// Prefers the machine readable `datetime` of a `<time>` over the date as displayed.
+			let date = node.parent()
+						   .and_then(|item| item.find(&selectors.chapter_dates).next())
+						   .map(|date| date.attr("datetime").map_or_else(|| date.text(), str::to_owned))
+						   .map(|date| collapse_whitespace(&date))
+						   .filter(|date| !date.is_empty());
+
+			chapters.push(Chapter {
+				index,
+				title,
+				link,
+				numbered,
+				volume,
+				occurrence: 1,
+				date,
+			});
+		}
+
+		Ok(chapters)
+	}
+
+	fn toc_pages(&self, doc: &Document, url: &Url) -> Vec<Url> {
+		let base = base_url(doc, url);
+		let numbered = doc.find(Class("pagination").descendant(Name("a")));
+		let next = doc.find(Name("a").and(Attr("rel", "next")));
+		numbered.chain(next)
+				.filter_map(|node| node.attr("href"))
+				.filter(|href| !href.starts_with('#') && !href.starts_with("javascript:"))
+				.filter_map(|href| resolve_href(&base, href).ok())
+				.collect()
+	}
+
+	fn parse_chapter(&self, doc: &Document, url: &Url) -> Result<String> {
+		let url = &base_url(doc, url);
+		for selector in &selectors().content {
+			let mut content = String::new();
+			try_with!(content, doc, url, selector);
+			let content = finish_scene_breaks(&content);
+			if !content.is_empty() {
+				return Ok(content);
+			}
+		}
+		let mut content = String::new();
+		push_span_paragraphs(&mut content, doc);
+		Ok(finish_scene_breaks(&content))
+	}
+
+	fn is_chapter_page(&self, doc: &Document) -> bool {
+		doc.find(Class("fr-view")).next().is_some()
+			|| selectors().content.iter().any(|selector| doc.find(selector).next().is_some())
+	}
+
+	fn next_chapter_page(&self, doc: &Document, url: &Url, chapter: &Url) -> Option<Url> {
+		let base = base_url(doc, url);
+		doc.find(&selectors().next_page)
+		   .filter_map(|node| Some((next_page_label(&node.text())?, node.attr("href")?)))
+		   .filter(|(_, href)| !href.starts_with('#') && !href.starts_with("javascript:"))
+		   .filter_map(|(labelled, href)| Some((labelled, resolve_href(&base, href).ok()?)))
+		   .map(|(labelled, mut next)| {
+			   next.set_fragment(None);
+			   (labelled, next)
+		   })
+		   // A bare "Next" is only taken for the next page when it stays within the chapter, wherever it was served from.
+		   .find(|(labelled, next)| next.host_str() == url.host_str() && (*labelled || continues_chapter(next, chapter) || continues_chapter(next, url)))
+		   .map(|(_, next)| next)
+	}
+
+	fn book_page(&self, doc: &Document, url: &Url) -> Option<Url> {
+		let base = base_url(doc, url);
+		let linked = doc.find(Name("a"))
+						.filter(|node| is_contents_label(&node.text()) || node.attr("rel").is_some_and(|rel| rel == "index" || rel == "contents"))
+						.filter_map(|node| node.attr("href"))
+						.filter_map(|href| resolve_href(&base, href).ok())
+						.find(|book| book.host_str() == url.host_str() && book.path() != url.path());
+		if linked.is_some() {
+			return linked;
+		}
+
+		// Chapters live beneath their book, at `/novel/<book>/<chapter>`.
+		let segments: Vec<&str> = url.path_segments()?.filter(|segment| !segment.is_empty()).collect();
+		match segments.as_slice() {
+			["novel", book, _] => {
+				let mut book_url = url.clone();
+				book_url.set_path(&format!("/novel/{}", book));
+				book_url.set_query(None);
+				book_url.set_fragment(None);
+				Some(book_url)
+			}
+			_ => None,
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::check_duplicates;
+
+	fn chapter_content(html: &str) -> String {
+		let url = Url::parse("https://www.wuxiaworld.com/novel/foo/foo-chapter-1").unwrap();
+		WuxiaWorldAdapter.parse_chapter(&Document::from(html), &url).unwrap()
+	}
+
+	fn chapter_list(titles: &[&str]) -> Vec<Chapter> {
+		let items: String = titles.iter()
+								  .enumerate()
+								  .map(|(i, title)| format!(r#"<li class="chapter-item"><a href="/novel/foo/chapter-{}">{}</a></li>"#, i, title))
+								  .collect();
+		let url = Url::parse("https://www.wuxiaworld.com/novel/foo").unwrap();
+		let mut chapters = WuxiaWorldAdapter.parse_chapter_list(&Document::from(format!("<ul>{}</ul>", items).as_str()), &url).unwrap();
+		synthesize_indices(&mut chapters);
+		chapters
+	}
+
+	fn indices(chapters: &[Chapter]) -> Vec<(u32, &str)> {
+		chapters.iter()
+				.map(|chapter| (chapter.index, chapter.title.as_str()))
+				.collect()
+	}
+
+	#[test]
+	fn prologues_come_before_the_first_chapter() {
+		let chapters = chapter_list(&["Prologue", "Chapter 1 - Start", "Chapter 2 - Next"]);
+		assert_eq!(indices(&chapters), vec![(0, "Prologue"), (1, "Start"), (2, "Next")]);
+		assert!(!chapters[0].numbered);
+	}
+
+	#[test]
+	fn unnumbered_chapters_keep_their_listed_place() {
+		let mut chapters = chapter_list(&["Chapter 1 - Start", "Interlude", "Chapter 2 - Next", "Epilogue", "Side Story: Before"]);
+		assert_eq!(check_duplicates(&mut chapters, true).unwrap(), 0);
+		chapters.sort_by_key(|chapter| chapter.index);
+		assert_eq!(indices(&chapters), vec![(1, "Start"), (1, "Interlude"), (2, "Next"), (2, "Epilogue"), (2, "Side Story: Before")]);
+		let stems: Vec<String> = chapters.iter().map(Chapter::file_stem).collect();
+		assert_eq!(stems, ["chapter_1", "chapter_1_2", "chapter_2", "chapter_2_2", "chapter_2_3"]);
+	}
+
+	/// The next page found on a chapter page linking to `href` as `label`.
+	fn next_page(label: &str, href: &str) -> Option<String> {
+		let url = Url::parse("https://www.wuxiaworld.com/novel/foo/chapter-1").unwrap();
+		let html = format!(r#"<div class="fr-view"><p>Words here.</p></div><a href="{}">{}</a>"#, href, label);
+		WuxiaWorldAdapter.next_chapter_page(&Document::from(html.as_str()), &url, &url)
+						 .map(|next| next.to_string())
+	}
+
+	#[test]
+	fn a_bare_next_to_the_next_chapter_is_not_a_next_page() {
+		assert_eq!(next_page("Next", "/novel/foo/chapter-2"), None);
+		assert_eq!(next_page("Next »", "https://www.wuxiaworld.com/novel/foo/chapter-2"), None);
+	}
+
+	#[test]
+	fn a_bare_next_within_the_chapter_is_a_next_page() {
+		assert_eq!(next_page("Next", "?page=2").as_deref(), Some("https://www.wuxiaworld.com/novel/foo/chapter-1?page=2"));
+		assert_eq!(next_page("Next", "/novel/foo/chapter-1/2").as_deref(), Some("https://www.wuxiaworld.com/novel/foo/chapter-1/2"));
+	}
+
+	#[test]
+	fn next_page_links_are_followed_anywhere_on_the_site() {
+		assert_eq!(next_page("Next Page", "/novel/foo/chapter-1-part-2").as_deref(), Some("https://www.wuxiaworld.com/novel/foo/chapter-1-part-2"));
+		assert_eq!(next_page("Next Page", "https://example.com/novel/foo/chapter-1/2"), None);
+		assert_eq!(next_page("Next Chapter", "/novel/foo/chapter-2"), None);
+	}
+
+	#[test]
+	fn scripts_styles_and_comments_are_left_out_of_chapters() {
+		let content = chapter_content(r#"<div class="fr-view"><p>Words here.<script>var ad = load();</script><!-- tracking --></p><style>p { color: red; }</style><noscript>Enable JavaScript</noscript><p>More words.</p></div>"#);
+		assert_eq!(content, "<p>Words here.</p>\n<p>More words.</p>\n");
+	}
+
+	#[test]
+	fn only_scripts_styles_and_comments_are_hidden() {
+		let doc = Document::from("<div><script>a</script><style>b</style><noscript>c</noscript><!-- d --><p>e</p></div>");
+		let div = doc.find(Name("div")).next().unwrap();
+		let hidden: Vec<bool> = div.children().map(|node| is_hidden(&node)).collect();
+		assert_eq!(hidden, vec![true, true, true, true, false]);
+	}
+
+	fn resolve(base: &str, href: &str) -> String {
+		resolve_href(&Url::parse(base).unwrap(), href).unwrap().to_string()
+	}
+
+	#[test]
+	fn relative_hrefs_resolve_against_the_directory_of_the_base() {
+		assert_eq!(resolve("https://www.wuxiaworld.com/novel/foo/", "chapter-1"), "https://www.wuxiaworld.com/novel/foo/chapter-1");
+		assert_eq!(resolve("https://www.wuxiaworld.com/novel/foo", "chapter-1"), "https://www.wuxiaworld.com/novel/chapter-1");
+		assert_eq!(resolve("https://www.wuxiaworld.com/novel/foo/", "../bar/chapter-1"), "https://www.wuxiaworld.com/novel/bar/chapter-1");
+		assert_eq!(resolve("https://www.wuxiaworld.com/novel/foo/", " chapter-1 \n"), "https://www.wuxiaworld.com/novel/foo/chapter-1");
+	}
+
+	#[test]
+	fn root_relative_hrefs_keep_only_the_host() {
+		assert_eq!(resolve("https://www.wuxiaworld.com/novel/foo/?page=2#top", "/novel/bar"), "https://www.wuxiaworld.com/novel/bar");
+	}
+
+	#[test]
+	fn absolute_hrefs_replace_the_base() {
+		assert_eq!(resolve("https://www.wuxiaworld.com/novel/foo/", "http://cdn.example.com/cover.jpg"), "http://cdn.example.com/cover.jpg");
+	}
+
+	#[test]
+	fn protocol_relative_hrefs_keep_only_the_scheme() {
+		assert_eq!(resolve("https://www.wuxiaworld.com/novel/foo/", "//cdn.example.com/cover.jpg"), "https://cdn.example.com/cover.jpg");
+		assert_eq!(resolve("http://www.wuxiaworld.com/novel/foo/", "//cdn.example.com/cover.jpg"), "http://cdn.example.com/cover.jpg");
+	}
+
+	#[test]
+	fn chapter_text_with_angle_brackets_is_escaped() {
+		let content = chapter_content(r#"<div class="fr-view"><p>&lt;System&gt;: Quest accepted &amp; rewarded.</p></div>"#);
+		assert_eq!(content, "<p>&lt;System&gt;: Quest accepted &amp; rewarded.</p>\n");
+	}
+}
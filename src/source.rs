@@ -0,0 +1,132 @@
+use reqwest::Client;
+use reqwest::header::{CONTENT_ENCODING, CONTENT_TYPE};
+use std::fs::File;
+use std::io::{self, BufReader, Read};
+use std::path::PathBuf;
+use super::errors::*;
+use super::{CookieJar, FetchOptions, decode_body, execute_with_retry, image_mime_from_path};
+use url::Url;
+
+/// A page or image, as it was fetched.
+pub struct Fetched {
+	/// Where it was eventually served from, after any redirects.
+	pub url: Url,
+	/// The mime type it was served as, if known.
+	pub mime: Option<String>,
+	/// The charset it was served with, if it was given one.
+	pub charset: Option<String>,
+	/// How many bytes long `body` is, if that's known up front.
+	pub length: Option<u64>,
+	pub body: Box<dyn Read>,
+}
+
+/// Where the book's pages and images come from, so they can be read from disk just as well as fetched.
+pub trait Source: Sync {
+	fn get(&self, url: &Url, fetch: &FetchOptions) -> Result<Fetched>;
+}
+
+/// Fetches everything over HTTP, retrying as described by `FetchOptions`, with the cookies in `cookies`.
+pub struct HttpSource {
+	client: Client,
+	cookies: CookieJar,
+}
+
+impl HttpSource {
+	pub fn new(client: Client, cookies: CookieJar) -> HttpSource {
+		HttpSource {
+			client,
+			cookies,
+		}
+	}
+}
+
+impl Source for HttpSource {
+	fn get(&self, url: &Url, fetch: &FetchOptions) -> Result<Fetched> {
+		let res = execute_with_retry(&self.client, &self.cookies, url, fetch)?;
+
+		let content_type = res.headers()
+							  .get(CONTENT_TYPE)
+							  .and_then(|value| value.to_str().ok())
+							  .unwrap_or_default();
+		let mime = content_type.split(';')
+							   .next()
+							   .map(|value| value.trim().to_lowercase())
+							   .filter(|value| !value.is_empty());
+		let charset = content_type.split(';')
+								  .skip(1)
+								  .filter_map(|param| param.split_once('='))
+								  .find(|(key, _)| key.trim().eq_ignore_ascii_case("charset"))
+								  .map(|(_, value)| value.trim().trim_matches('"').to_owned());
+		// The length of an encoded body is its length before it's decoded.
+		let encoded = res.headers()
+						 .get(CONTENT_ENCODING)
+						 .and_then(|value| value.to_str().ok())
+						 .is_some_and(|value| !value.trim().is_empty() && !value.trim().eq_ignore_ascii_case("identity"));
+		let length = if encoded { None } else { res.content_length() };
+
+		Ok(Fetched {
+			url: res.url().clone(),
+			mime,
+			charset,
+			length,
+			body: decode_body(res)?,
+		})
+	}
+}
+
+/// Reads pages saved from a browser out of a directory, for debugging selectors or for sites that can only be
+/// reached from one.
+///
+/// A URL is read from the file named after its last path segment, with `.html` added unless it already has an
+/// extension: `https://www.wuxiaworld.com/novel/foo/chapter-1` is read from `<dir>/chapter-1.html`. The book
+/// page can also be saved as `index.html`.
+pub struct OfflineSource {
+	dir: PathBuf,
+	book: Url,
+}
+
+impl OfflineSource {
+	pub fn new(dir: PathBuf, book: Url) -> OfflineSource {
+		OfflineSource {
+			dir,
+			book,
+		}
+	}
+
+	fn path_for(&self, url: &Url) -> PathBuf {
+		let segment = url.path_segments()
+						 .and_then(|mut segments| segments.rfind(|segment| !segment.is_empty()))
+						 .unwrap_or("index");
+		if segment.contains('.') {
+			self.dir.join(segment)
+		} else {
+			self.dir.join(format!("{}.html", segment))
+		}
+	}
+}
+
+impl Source for OfflineSource {
+	fn get(&self, url: &Url, _fetch: &FetchOptions) -> Result<Fetched> {
+		let mut path = self.path_for(url);
+		if !path.is_file() && *url == self.book {
+			path = self.dir.join("index.html");
+		}
+
+		let file = match File::open(&path) {
+			Ok(file) => file,
+			Err(e) if e.kind() == io::ErrorKind::NotFound => {
+				return Err(Error::with_chain(e, ErrorKind::NotFound(format!("No saved page for \"{}\" at \"{}\"", url, path.display()))));
+			}
+			Err(e) => return Err(Error::from(e)).chain_err(|| format!("Unable to read the saved page for \"{}\" at \"{}\"", url, path.display())),
+		};
+
+		Ok(Fetched {
+			url: url.clone(),
+			mime: path.to_str().and_then(image_mime_from_path).map(str::to_owned),
+			// Saved pages are left to declare their own.
+			charset: None,
+			length: file.metadata().ok().map(|metadata| metadata.len()),
+			body: Box::new(BufReader::new(file)),
+		})
+	}
+}
@@ -0,0 +1,96 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use super::errors::*;
+use super::render::SOURCE_LINK_MARKER;
+use super::{Image, PLACEHOLDER_MARKER, image_mime_from_path};
+use zip::ZipArchive;
+
+/// What can be salvaged from an epub generated by an earlier run, so `--update` only fetches what's new.
+pub struct ExistingEpub {
+	/// The content of every chapter, by file stem, with its images already pointing at `images`.
+	pub pages: HashMap<String, String>,
+	/// The chapter images embedded in the epub, along with their resource paths.
+	pub images: Vec<(String, Image)>,
+}
+
+impl ExistingEpub {
+	/// The highest chapter index in the epub, if it holds any chapters.
+	pub fn highest_index(&self) -> Option<u32> {
+		self.pages.keys()
+				  .filter_map(|stem| stem.strip_prefix("chapter_"))
+				  .filter_map(|rest| rest.split('_').next()?.parse::<u32>().ok())
+				  .max()
+	}
+}
+
+/// Reads the chapters and images back out of an epub written by `build_epub`.
+pub fn read_existing_epub(path: &Path) -> Result<ExistingEpub> {
+	let file = File::open(path)
+		.chain_err(|| format!("Unable to open existing epub: \"{}\"", path.display()))?;
+	let mut archive = ZipArchive::new(file)
+		.chain_err(|| format!("Unable to read existing epub: \"{}\"", path.display()))?;
+
+	let mut pages = HashMap::new();
+	let mut images = Vec::new();
+	for i in 0..archive.len() {
+		let mut entry = archive.by_index(i)
+							   .chain_err(|| format!("Unable to read entry {} of \"{}\"", i, path.display()))?;
+		let name = match entry.name().strip_prefix("OEBPS/") {
+			Some(name) => name.to_owned(),
+			None => continue,
+		};
+
+		if let Some(stem) = name.strip_prefix("chapter_").and_then(|rest| rest.strip_suffix(".xhtml")) {
+			let mut document = String::new();
+			entry.read_to_string(&mut document)
+				 .chain_err(|| format!("Unable to read \"{}\" from the existing epub.", name))?;
+			// Anything that doesn't look like one of our own chapters (or only stands in for one) is simply fetched again.
+			if let Some(body) = chapter_body(&document).filter(|body| !body.contains(PLACEHOLDER_MARKER)) {
+				pages.insert(unpadded_stem(stem), unindent(body));
+			}
+		} else if name.starts_with("images/") {
+			let mime = match image_mime_from_path(&name) {
+				Some(mime) => mime.to_owned(),
+				None => continue,
+			};
+			let mut data = Vec::new();
+			entry.read_to_end(&mut data)
+				 .chain_err(|| format!("Unable to read \"{}\" from the existing epub.", name))?;
+			images.push((name, Image { data, mime }));
+		}
+	}
+
+	Ok(ExistingEpub {
+		pages,
+		images,
+	})
+}
+
+/// Turns the part of a (possibly zero-padded) chapter's file name after `chapter_` back into `Chapter::file_stem`.
+fn unpadded_stem(stem: &str) -> String {
+	let (index, occurrence) = match stem.find('_') {
+		Some(i) => stem.split_at(i),
+		None => (stem, ""),
+	};
+	let index = index.trim_start_matches('0');
+	format!("chapter_{}{}", if index.is_empty() { "0" } else { index }, occurrence)
+}
+
+/// `body` without the indentation `--pretty-xhtml` gives its lines, so it's the same either way.
+fn unindent(body: &str) -> String {
+	body.lines()
+		.map(str::trim_start)
+		.filter(|line| !line.trim().is_empty())
+		.map(|line| format!("{}\n", line))
+		.collect()
+}
+
+/// The content between the chapter's heading and the end of its body (or its source link), as wrapped by
+/// `render_page_xhtml`.
+fn chapter_body(document: &str) -> Option<&str> {
+	let start = document.find("</h1>\n")? + "</h1>\n".len();
+	let end = document.rfind(SOURCE_LINK_MARKER).or_else(|| document.rfind("</body>"))?;
+	document.get(start..end)
+}
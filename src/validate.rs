@@ -0,0 +1,80 @@
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use super::errors::*;
+use xml::reader::{EventReader, XmlEvent};
+use zip::ZipArchive;
+
+/// Checks the basic structure of an epub: that it's a zip led by the `mimetype` entry, points at a package
+/// document, and that every XML document inside of it is well-formed.
+///
+/// Returns how many documents were checked, failing on the first problem found.
+pub fn validate_epub(path: &Path) -> Result<usize> {
+	let file = File::open(path)
+		.chain_err(|| format!("Unable to open \"{}\" for validation.", path.display()))?;
+	let mut archive = ZipArchive::new(file)
+		.chain_err(|| format!("\"{}\" isn't a valid zip file.", path.display()))?;
+
+	{
+		let mut mimetype = archive.by_index(0)
+								  .chain_err(|| "The epub is empty.")?;
+		if mimetype.name() != "mimetype" {
+			bail!("The first entry is \"{}\" rather than \"mimetype\".", mimetype.name());
+		}
+		let mut contents = String::new();
+		mimetype.read_to_string(&mut contents)
+				.chain_err(|| "Unable to read the mimetype entry.")?;
+		if contents.trim() != "application/epub+zip" {
+			bail!("The mimetype entry holds \"{}\" rather than \"application/epub+zip\".", contents.trim());
+		}
+	}
+
+	let container = read_entry(&mut archive, "META-INF/container.xml")?;
+	check_well_formed("META-INF/container.xml", &container)?;
+	let package = package_path(&container)
+		.chain_err(|| "The container doesn't point at a package document.")?;
+	let opf = read_entry(&mut archive, &package)?;
+	check_well_formed(&package, &opf)?;
+
+	let documents: Vec<String> = archive.file_names()
+										.filter(|name| name.ends_with(".xhtml") || name.ends_with(".ncx"))
+										.map(str::to_owned)
+										.collect();
+	for name in &documents {
+		let document = read_entry(&mut archive, name)?;
+		check_well_formed(name, &document)?;
+	}
+
+	Ok(documents.len() + 2)
+}
+
+fn read_entry(archive: &mut ZipArchive<File>, name: &str) -> Result<String> {
+	let mut entry = archive.by_name(name)
+						   .chain_err(|| format!("The epub is missing \"{}\".", name))?;
+	let mut contents = String::new();
+	entry.read_to_string(&mut contents)
+		 .chain_err(|| format!("Unable to read \"{}\" as UTF-8.", name))?;
+	Ok(contents)
+}
+
+fn check_well_formed(name: &str, document: &str) -> Result<()> {
+	for event in EventReader::from_str(document) {
+		event.chain_err(|| format!("\"{}\" isn't well-formed XML.", name))?;
+	}
+	Ok(())
+}
+
+/// The `full-path` of the first `rootfile` listed in the container.
+fn package_path(container: &str) -> Option<String> {
+	EventReader::from_str(container)
+		.into_iter()
+		.filter_map(|event| match event {
+			Ok(XmlEvent::StartElement { name, attributes, .. }) if name.local_name == "rootfile" => {
+				attributes.into_iter()
+						  .find(|attribute| attribute.name.local_name == "full-path")
+						  .map(|attribute| attribute.value)
+			}
+			_ => None,
+		})
+		.next()
+}